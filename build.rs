@@ -1,6 +1,6 @@
 use std::fs::File;
 use std::path::Path;
-use std::{env, io};
+use std::{env, fs, io};
 
 use flate2::Compression;
 use flate2::write::GzEncoder;
@@ -12,15 +12,25 @@ async fn main() -> io::Result<()> {
     let out_dir = env::var_os("OUT_DIR").unwrap();
     let cache_path = Path::new(&out_dir).join("game_data.json");
 
+    let out_path = Path::new(&out_dir).join("game_data.gz");
     let mut db = anime_game_data::AnimeGameData::new_with_cache(&cache_path);
     if db.needs_update().await.unwrap() {
         db.update().await.unwrap();
-        let out_path = Path::new(&out_dir).join("game_data.gz");
-        let f = File::create(out_path).unwrap();
+        let f = File::create(&out_path).unwrap();
         let writer = GzEncoder::new(f, Compression::best());
         db.save_to_writer(writer).unwrap();
     }
 
+    // Identify the embedded game data so that runtime updates downloaded on
+    // top of a different copy can be ignored.  FNV-1a of the gzipped data,
+    // which flate2 writes without a timestamp.
+    let version = fs::read(&out_path)?
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325u64, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+    println!("cargo:rustc-env=EMBEDDED_GAME_DATA_VERSION={version:016x}");
+
     // Add icon to windows binary.
     if env::var_os("CARGO_CFG_WINDOWS").is_some() {
         WindowsResource::new()
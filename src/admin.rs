@@ -22,9 +22,14 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+#[cfg(windows)]
+pub fn is_admin() -> bool {
+    unsafe { windows::Win32::UI::Shell::IsUserAnAdmin().into() }
+}
+
 #[cfg(windows)]
 pub fn ensure_admin() {
-    if unsafe { windows::Win32::UI::Shell::IsUserAnAdmin().into() } {
+    if is_admin() {
         tracing::info!("Running with admin privileges");
         return;
     }
@@ -76,25 +81,380 @@ pub fn ensure_admin() {
     std::process::exit(0);
 }
 
+/// Whether we are running as root or have CAP_NET_RAW.
 #[cfg(unix)]
-pub fn ensure_admin() {
-    // We are happy if we are running as root or have CAP_NET_RAW
+pub fn has_capture_privileges() -> bool {
     let is_root = unsafe { libc::geteuid() } == 0;
     if is_root {
-        return;
+        return true;
     }
 
     let has_net_raw_result =
         caps::has_cap(None, caps::CapSet::Effective, caps::Capability::CAP_NET_RAW);
-    if has_net_raw_result.is_ok_and(|has_net_raw| has_net_raw) {
+    has_net_raw_result.is_ok_and(|has_net_raw| has_net_raw)
+}
+
+/// The user who started Irminsul through sudo, if we're running as root on
+/// their behalf.
+#[cfg(unix)]
+fn sudo_user() -> Option<(libc::uid_t, libc::gid_t)> {
+    if unsafe { libc::geteuid() } != 0 {
+        return None;
+    }
+
+    let uid = std::env::var("SUDO_UID").ok()?.parse().ok()?;
+    let gid = std::env::var("SUDO_GID").ok()?.parse().ok()?;
+    (uid != 0).then_some((uid, gid))
+}
+
+/// Point `HOME` at the sudo user's home directory so the storage dir is
+/// theirs rather than root's.  Must be called first thing in `main`, before
+/// tracing and the tokio runtime start their threads, since changing the
+/// environment isn't thread safe.
+#[cfg(unix)]
+pub fn use_sudo_user_home() {
+    use std::ffi::{CStr, OsStr};
+    use std::os::unix::ffi::OsStrExt;
+
+    let Some((uid, _)) = sudo_user() else {
+        return;
+    };
+
+    let passwd = unsafe { libc::getpwuid(uid) };
+    if passwd.is_null() {
         return;
     }
+    let home = unsafe { CStr::from_ptr((*passwd).pw_dir) };
+    // SAFETY: called first thing in main, before tracing or the tokio runtime
+    // have started any threads.
+    unsafe { std::env::set_var("HOME", OsStr::from_bytes(home.to_bytes())) };
+}
+
+/// Hand `path` over to the sudo user.  Does nothing unless running as root
+/// through sudo.
+#[cfg(unix)]
+pub fn chown_to_sudo_user(path: &std::path::Path) {
+    let Some((uid, gid)) = sudo_user() else {
+        return;
+    };
 
-    show_packet_capture_permissions_missing_dialog();
+    if let Err(e) = std::os::unix::fs::lchown(path, Some(uid), Some(gid)) {
+        tracing::warn!("Unable to change owner of {path:?}: {e}");
+    }
 }
 
+/// Hand the storage dir and everything in it over to the sudo user.
 #[cfg(unix)]
-fn show_packet_capture_permissions_missing_dialog() {
+pub fn chown_storage_dir_to_sudo_user() {
+    fn chown_recursive(path: &std::path::Path) {
+        chown_to_sudo_user(path);
+        if path.is_symlink() || !path.is_dir() {
+            return;
+        }
+        let Ok(entries) = std::fs::read_dir(path) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            chown_recursive(&entry.path());
+        }
+    }
+
+    if sudo_user().is_none() {
+        return;
+    }
+    if let Some(dir) = eframe::storage_dir(crate::APP_ID) {
+        chown_recursive(&dir);
+    }
+}
+
+/// Switch from root to the user who started Irminsul with sudo and drop all
+/// capabilities.  Already open capture handles keep working, capture started
+/// afterwards has to go through the capture helper.  Returns whether there
+/// was a sudo user to switch to.
+#[cfg(target_os = "linux")]
+pub fn drop_privileges() -> anyhow::Result<bool> {
+    use std::ffi::CString;
+
+    use anyhow::bail;
+
+    let Some((uid, gid)) = sudo_user() else {
+        return Ok(false);
+    };
+
+    chown_storage_dir_to_sudo_user();
+
+    let user = std::env::var("SUDO_USER")
+        .ok()
+        .and_then(|user| CString::new(user).ok());
+    unsafe {
+        let groups_set = match &user {
+            Some(user) => libc::initgroups(user.as_ptr(), gid as _) == 0,
+            None => libc::setgroups(0, std::ptr::null()) == 0,
+        };
+        if !groups_set {
+            bail!("setting groups failed: {}", std::io::Error::last_os_error());
+        }
+        if libc::setgid(gid) != 0 {
+            bail!("setgid({gid}) failed: {}", std::io::Error::last_os_error());
+        }
+        if libc::setuid(uid) != 0 {
+            bail!("setuid({uid}) failed: {}", std::io::Error::last_os_error());
+        }
+    }
+
+    for set in [
+        caps::CapSet::Effective,
+        caps::CapSet::Permitted,
+        caps::CapSet::Inheritable,
+        caps::CapSet::Ambient,
+    ] {
+        if let Err(e) = caps::clear(None, set) {
+            tracing::warn!("Unable to clear {set:?} capabilities: {e}");
+        }
+    }
+
+    tracing::info!("Dropped root privileges, now running as uid {uid} gid {gid}");
+    Ok(true)
+}
+
+#[cfg(unix)]
+pub fn ensure_admin() {
+    if has_capture_privileges() {
+        return;
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        let exe = std::env::current_exe().ok();
+        let unsupported = exe.as_deref().and_then(file_capabilities_unsupported);
+        let helper_available = crate::capture::helper::needed();
+
+        // Capture will go through a helper started with pkexec instead.
+        if helper_available && let Some(reason) = unsupported {
+            tracing::info!("{reason}, capturing through the privileged capture helper");
+            return;
+        }
+
+        if helper_available && helper_chosen() {
+            tracing::info!("Capturing through the privileged capture helper as chosen before");
+            return;
+        }
+
+        if helper_available && let Some(exe) = exe {
+            if show_capability_setup_dialog(exe) {
+                tracing::info!("Capturing through the privileged capture helper");
+                remember_helper_choice();
+                return;
+            }
+            std::process::exit(1);
+        }
+
+        show_packet_capture_permissions_missing_dialog(unsupported);
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    show_packet_capture_permissions_missing_dialog(None);
+}
+
+/// File in the storage dir recording that the user chose to capture through
+/// the helper rather than grant capabilities.  Deleting it brings the choice
+/// back.
+#[cfg(target_os = "linux")]
+fn helper_choice_path() -> Option<std::path::PathBuf> {
+    eframe::storage_dir(crate::APP_ID).map(|dir| dir.join("use_capture_helper"))
+}
+
+#[cfg(target_os = "linux")]
+fn helper_chosen() -> bool {
+    helper_choice_path().is_some_and(|path| path.exists())
+}
+
+#[cfg(target_os = "linux")]
+fn remember_helper_choice() {
+    let Some(path) = helper_choice_path() else {
+        return;
+    };
+    let result = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|()| std::fs::write(&path, ""));
+    if let Err(e) = result {
+        tracing::warn!("Unable to save capture helper choice to {path:?}: {e}");
+    }
+}
+
+/// Why file capabilities granted with `setcap` wouldn't take effect for
+/// `exe`.
+#[cfg(target_os = "linux")]
+fn file_capabilities_unsupported(exe: &std::path::Path) -> Option<&'static str> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    if std::env::var_os("APPIMAGE").is_some() {
+        return Some("Irminsul is running from an AppImage, which can't carry file capabilities");
+    }
+
+    let path = CString::new(exe.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } == 0 && stat.f_flag & libc::ST_NOSUID != 0
+    {
+        return Some(
+            "Irminsul is on a file system mounted nosuid, which ignores file capabilities",
+        );
+    }
+
+    None
+}
+
+/// Whether CAP_NET_RAW comes from the executable's file capabilities rather
+/// than from running as root.
+#[cfg(target_os = "linux")]
+pub fn has_file_capture_capability() -> bool {
+    let is_root = unsafe { libc::getuid() } == 0;
+    !is_root
+        && caps::has_cap(None, caps::CapSet::Permitted, caps::Capability::CAP_NET_RAW)
+            .is_ok_and(|has_net_raw| has_net_raw)
+}
+
+/// Grant CAP_NET_RAW to `exe` with `setcap`, asking for the password through
+/// pkexec.
+#[cfg(target_os = "linux")]
+pub fn grant_capture_capability(exe: &std::path::Path) -> anyhow::Result<()> {
+    use anyhow::{Context, bail};
+
+    // setcap usually lives in sbin, which isn't always in a user's PATH.
+    let setcap = std::env::split_paths(&std::env::var_os("PATH").unwrap_or_default())
+        .chain(["/usr/sbin".into(), "/sbin".into()])
+        .map(|dir| dir.join("setcap"))
+        .find(|path| path.is_file())
+        .context("setcap not found, install libcap")?;
+
+    tracing::info!("Granting CAP_NET_RAW to {exe:?}");
+    let status = std::process::Command::new("pkexec")
+        .arg(setcap)
+        .arg("cap_net_raw=ep")
+        .arg(exe)
+        .status()
+        .context("can't run pkexec")?;
+
+    match status.code() {
+        Some(0) => Ok(()),
+        // pkexec exits with 126 when the dialog is dismissed and 127 when
+        // authorization fails.
+        Some(126) | Some(127) => bail!("authorization was denied"),
+        _ => bail!("setcap failed: {status}"),
+    }
+}
+
+/// Replace this process with a fresh copy of `exe` so it picks up newly
+/// granted capabilities.  Only returns on failure.
+#[cfg(target_os = "linux")]
+fn relaunch(exe: &std::path::Path) -> std::io::Error {
+    use std::os::unix::process::CommandExt;
+
+    tracing::info!("Relaunching {exe:?}");
+    std::process::Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .exec()
+}
+
+/// Offer to grant capture permissions and relaunch.  Returns whether the user
+/// chose to capture through the helper instead.
+#[cfg(target_os = "linux")]
+fn show_capability_setup_dialog(exe: std::path::PathBuf) -> bool {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::sync::mpsc;
+
+    let options = eframe::NativeOptions {
+        viewport: egui::ViewportBuilder::default()
+            .with_inner_size([500.0, 220.0])
+            .with_resizable(true),
+        ..Default::default()
+    };
+
+    let use_helper = Rc::new(Cell::new(false));
+    let use_helper_clicked = use_helper.clone();
+    let mut grant_rx: Option<mpsc::Receiver<anyhow::Result<()>>> = None;
+    let mut error: Option<String> = None;
+
+    let _ = eframe::run_simple_native(
+        "Irminsul requires packet capture permissions",
+        options,
+        move |ctx, _frame| {
+            if let Some(rx) = &grant_rx {
+                match rx.try_recv() {
+                    Ok(Ok(())) => {
+                        let e = relaunch(&exe);
+                        error = Some(format!("Unable to relaunch Irminsul: {e}"));
+                        grant_rx = None;
+                    }
+                    Ok(Err(e)) => {
+                        error = Some(format!("Unable to grant permissions: {e:#}"));
+                        grant_rx = None;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => {
+                        ctx.request_repaint_after(std::time::Duration::from_millis(100));
+                    }
+                    Err(mpsc::TryRecvError::Disconnected) => grant_rx = None,
+                }
+            }
+
+            egui::CentralPanel::default().show(ctx, |ui| {
+                ui.vertical_centered(|ui| {
+                    ui.label("Irminsul needs the CAP_NET_RAW capability to capture packets.");
+                    ui.add_space(5.0);
+                    ui.label(
+                        "It can be granted once (and again after every update) and Irminsul \
+                        will restart with it.  Otherwise Irminsul will ask for your password \
+                        every time capture starts.",
+                    );
+                    ui.add_space(10.0);
+                    if let Some(error) = &error {
+                        ui.colored_label(ui.visuals().error_fg_color, error);
+                    }
+                });
+
+                ui.with_layout(
+                    egui::Layout::bottom_up(egui::Align::Center).with_cross_justify(true),
+                    |ui| {
+                        ui.add_space(10.0);
+                        if ui.button("Quit").clicked() {
+                            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                        }
+                        if ui.button("Ask every time").clicked() {
+                            use_helper_clicked.set(true);
+                            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                        }
+                        let granting = grant_rx.is_some();
+                        let label = if granting {
+                            "Granting permissions..."
+                        } else {
+                            "Grant permissions and restart"
+                        };
+                        if ui
+                            .add_enabled(!granting, egui::Button::new(label))
+                            .clicked()
+                        {
+                            let (tx, rx) = mpsc::channel();
+                            let exe = exe.clone();
+                            std::thread::spawn(move || {
+                                let _ = tx.send(grant_capture_capability(&exe));
+                            });
+                            grant_rx = Some(rx);
+                            error = None;
+                        }
+                    },
+                );
+            });
+        },
+    );
+
+    use_helper.get()
+}
+
+#[cfg(unix)]
+fn show_packet_capture_permissions_missing_dialog(capabilities_unsupported: Option<&'static str>) {
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([500.0, 200.0])
@@ -116,13 +476,17 @@ fn show_packet_capture_permissions_missing_dialog() {
                 ui.vertical_centered(|ui| {
                     ui.label("How to grant packet capture permissions:");
                     ui.add_space(5.0);
-                    ui.label("1. Grant CAP_NET_RAW to Irminsul (after every update):");
-                    ui.label(format!(
-                        "sudo setcap cap_net_raw=ep '{}' && '{}'",
-                        exe_path, exe_path
-                    ));
-                    ui.add_space(5.0);
-                    ui.label("2. Run Irminsul as root (every time):");
+                    if let Some(reason) = capabilities_unsupported {
+                        ui.label(format!("{reason}, so it has to run as root:"));
+                    } else {
+                        ui.label("1. Grant CAP_NET_RAW to Irminsul (after every update):");
+                        ui.label(format!(
+                            "sudo setcap cap_net_raw=ep '{}' && '{}'",
+                            exe_path, exe_path
+                        ));
+                        ui.add_space(5.0);
+                        ui.label("2. Run Irminsul as root (every time):");
+                    }
                     ui.label(format!("sudo '{}'", exe_path));
                     ui.add_space(10.0);
                     ui.label("Rerun Irminsul with --no-admin if you wish to proceed without packet capture")
@@ -3,10 +3,10 @@ use std::fs::File;
 use std::io::{BufWriter, Write};
 use std::path::PathBuf;
 use std::thread;
-use std::time::Instant;
+use std::time::{Instant, SystemTime};
 
 use anyhow::{Context as _, Result, anyhow};
-use chrono::Local;
+use chrono::{DateTime, Local};
 use egui::{
     Button, Color32, Context, DragValue, Id, Key, KeyboardShortcut, Modal, Modifiers, OpenUrl,
     PointerButton, RichText, Sense, ViewportCommand,
@@ -16,14 +16,29 @@ use egui_notify::Toasts;
 use serde::{Deserialize, Serialize};
 use tokio::sync::{mpsc, oneshot, watch};
 
-use crate::monitor::Monitor;
-use crate::player_data::ExportSettings;
+use crate::capture::{CaptureConfig, CaptureOverrides, DeviceInfo, PortRanges};
+use crate::game_data::GameDataSource;
+use crate::inspector::{Inspector, RecentCommands};
+use crate::monitor::{Monitor, MonitorChannels};
+use crate::packet_log::{self, PacketLogSettings};
+use crate::player_data::{ExportSettings, UnmappedIds};
 use crate::update::check_for_app_update;
 use crate::{
-    AppState, ConfirmationType, Message, ReloadHandle, State, TracingLevel, capture, open_log_dir,
-    wish,
+    APP_ID, AppState, CaptureStatus, CommandCatalog, ConfirmationType, KeyStatus, Message,
+    ReloadHandle, State, TracingLevel, capture, handlers, open_log_dir, wish,
 };
 
+/// Color of warning icons.
+const WARNING_COLOR: Color32 = Color32::from_rgb(0xe0, 0xa0, 0x00);
+
+/// Data categories shown in the capture section whether updated yet or not,
+/// with their labels.
+const DATA_CATEGORIES: [(&str, &str); 3] = [
+    (handlers::ITEMS, "Items"),
+    (handlers::AVATARS, "Characters"),
+    (handlers::ACHIEVEMENTS, "Achievements"),
+];
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct SavedAppState {
     export_settings: ExportSettings,
@@ -31,31 +46,34 @@ pub struct SavedAppState {
     auto_start_capture: bool,
     log_raw_packets: bool,
     #[serde(default)]
+    decode_logged_packets: bool,
+    #[serde(default)]
     tracing_level: TracingLevel,
+    #[serde(default)]
+    game_data_path: Option<PathBuf>,
+    #[serde(default)]
+    capture_config: CaptureConfig,
 }
 
 impl Default for SavedAppState {
     fn default() -> Self {
         Self {
-            export_settings: ExportSettings {
-                include_characters: true,
-                include_artifacts: true,
-                include_weapons: true,
-                include_materials: true,
-                fake_initialize_4th_line: false,
-                min_character_level: 1,
-                min_character_ascension: 0,
-                min_character_constellation: 0,
-                min_artifact_level: 0,
-                min_artifact_rarity: 5,
-                min_weapon_level: 1,
-                min_weapon_refinement: 0,
-                min_weapon_ascension: 0,
-                min_weapon_rarity: 3,
-            },
+            export_settings: ExportSettings::default(),
             auto_start_capture: false,
             log_raw_packets: false,
+            decode_logged_packets: false,
             tracing_level: Default::default(),
+            game_data_path: None,
+            capture_config: CaptureConfig::default(),
+        }
+    }
+}
+
+impl SavedAppState {
+    fn packet_log_settings(&self) -> PacketLogSettings {
+        PacketLogSettings {
+            enabled: self.log_raw_packets,
+            decode: self.decode_logged_packets,
         }
     }
 }
@@ -67,11 +85,28 @@ enum OptimizerExportTarget {
     File,
 }
 
+/// Options given on the command line.
+pub struct LaunchOptions {
+    pub capture_backend: capture::BackendType,
+    pub game_data_url: Option<String>,
+    pub game_data_path: Option<PathBuf>,
+    pub keys_path: Option<PathBuf>,
+    /// Capture options that take precedence over the saved ones for the
+    /// whole session.
+    pub capture_overrides: CaptureOverrides,
+    /// Packet log to replay once the monitor has started.
+    pub replay: Option<PathBuf>,
+}
+
 pub struct IrminsulApp {
     ui_message_tx: mpsc::UnboundedSender<Message>,
     state_rx: watch::Receiver<AppState>,
     wish_url_rx: watch::Receiver<Option<String>>,
-    log_packets_tx: watch::Sender<bool>,
+    log_packets_tx: watch::Sender<PacketLogSettings>,
+    capture_config_tx: watch::Sender<CaptureConfig>,
+    /// Capture options given on the command line, which take precedence over
+    /// the saved ones for the whole session.
+    capture_overrides: CaptureOverrides,
     tracing_reload_handle: ReloadHandle,
 
     toasts: Toasts,
@@ -79,7 +114,21 @@ pub struct IrminsulApp {
     power_tools_open: bool,
     bug_report_open: bool,
 
+    command_catalog_open: bool,
+    command_catalog_unhandled_only: bool,
+    command_catalog_by_count: bool,
+    command_catalog_rx: watch::Receiver<CommandCatalog>,
+
+    inspector_open: bool,
+    /// Tells the monitor to record commands for the inspector.
+    inspector_open_tx: watch::Sender<bool>,
+    inspector: Inspector,
+
     capture_settings_open: bool,
+    capture_devices: Option<Vec<DeviceInfo>>,
+    capture_include_text: String,
+    capture_exclude_text: String,
+    capture_ports_text: String,
 
     optimizer_settings_open: bool,
     optimizer_export_rx: Option<oneshot::Receiver<Result<String>>>,
@@ -87,6 +136,13 @@ pub struct IrminsulApp {
     optimizer_save_path: Option<PathBuf>,
     optimizer_export_target: OptimizerExportTarget,
 
+    game_data_dialog: Option<FileDialog>,
+
+    replay_dialog: Option<FileDialog>,
+    /// Packet log to replay once the monitor is running.
+    pending_replay: Option<PathBuf>,
+    replay_rx: Option<oneshot::Receiver<Result<usize>>>,
+
     restarting: bool,
 
     saved_state: SavedAppState,
@@ -111,18 +167,26 @@ impl<T, E: Display> ToastError<T> for std::result::Result<T, E> {
 
 fn start_async_runtime(
     egui_ctx: Context,
-    log_packets_rx: watch::Receiver<bool>,
+    log_packets_rx: watch::Receiver<PacketLogSettings>,
+    capture_config_rx: watch::Receiver<CaptureConfig>,
+    inspector_open_rx: watch::Receiver<bool>,
     capture_backend: capture::BackendType,
+    game_data_source: GameDataSource,
+    keys_path: Option<PathBuf>,
 ) -> (
     mpsc::UnboundedSender<Message>,
     watch::Receiver<AppState>,
     watch::Receiver<Option<String>>,
+    watch::Receiver<CommandCatalog>,
+    watch::Receiver<RecentCommands>,
 ) {
     tracing::info!("starting tokio async");
     let (ui_message_tx, mut ui_message_rx) = mpsc::unbounded_channel::<Message>();
 
     let (state_tx, state_rx) = watch::channel(AppState::new());
     let (wish_url_tx, wish_url_rx) = watch::channel(None);
+    let (command_catalog_tx, command_catalog_rx) = watch::channel(CommandCatalog::new());
+    let (recent_commands_tx, recent_commands_rx) = watch::channel(RecentCommands::new());
     let mut updater_state_rx = state_rx.clone();
     let updater_ctx = egui_ctx.clone();
     thread::spawn(move || {
@@ -155,33 +219,50 @@ fn start_async_runtime(
                 }
             });
             tracing::info!("Starting monitor");
-            let monitor = match Monitor::new(
+            let channels = MonitorChannels {
                 state_tx,
                 ui_message_rx,
-                log_packets_rx,
-                capture_backend,
-            )
-            .await
-            {
-                Ok(monitor) => monitor,
-                Err(e) => {
-                    tracing::error!("error loading monitor task: {e}");
-                    return;
-                }
+                log_packet_rx: log_packets_rx,
+                capture_config_rx,
+                command_catalog_tx,
+                inspector_open_rx,
+                recent_commands_tx,
             };
+            let monitor =
+                match Monitor::new(channels, capture_backend, game_data_source, keys_path).await {
+                    Ok(monitor) => monitor,
+                    Err(e) => {
+                        tracing::error!("error loading monitor task: {e}");
+                        return;
+                    }
+                };
             monitor.run().await;
         });
     });
     tracing::info!("started tokio");
-    (ui_message_tx, state_rx, wish_url_rx)
+    (
+        ui_message_tx,
+        state_rx,
+        wish_url_rx,
+        command_catalog_rx,
+        recent_commands_rx,
+    )
 }
 
 impl IrminsulApp {
     pub fn new(
         cc: &eframe::CreationContext<'_>,
         mut tracing_reload_handle: ReloadHandle,
-        capture_backend: capture::BackendType,
+        options: LaunchOptions,
     ) -> Self {
+        let LaunchOptions {
+            capture_backend,
+            game_data_url,
+            game_data_path,
+            keys_path,
+            capture_overrides,
+            replay,
+        } = options;
         egui_extras::install_image_loaders(&cc.egui_ctx);
         egui_material_icons::initialize(&cc.egui_ctx);
 
@@ -192,9 +273,28 @@ impl IrminsulApp {
         };
 
         tracing_reload_handle.set_filter(saved_state.tracing_level.get_filter());
-        let (log_packets_tx, log_packets_rx) = watch::channel(saved_state.log_raw_packets);
-        let (ui_message_tx, state_rx, wish_url_rx) =
-            start_async_runtime(cc.egui_ctx.clone(), log_packets_rx, capture_backend);
+        let (log_packets_tx, log_packets_rx) = watch::channel(saved_state.packet_log_settings());
+        let (capture_config_tx, capture_config_rx) = watch::channel(
+            saved_state
+                .capture_config
+                .clone()
+                .with_overrides(capture_overrides.clone()),
+        );
+        let (inspector_open_tx, inspector_open_rx) = watch::channel(false);
+        let game_data_source = GameDataSource::new(
+            game_data_url,
+            game_data_path.or_else(|| saved_state.game_data_path.clone()),
+        );
+        let (ui_message_tx, state_rx, wish_url_rx, command_catalog_rx, recent_commands_rx) =
+            start_async_runtime(
+                cc.egui_ctx.clone(),
+                log_packets_rx,
+                capture_config_rx,
+                inspector_open_rx,
+                capture_backend,
+                game_data_source,
+                keys_path,
+            );
 
         if saved_state.auto_start_capture {
             if let Err(e) = ui_message_tx.send(Message::StartCapture) {
@@ -202,22 +302,42 @@ impl IrminsulApp {
             }
         }
 
+        let capture_include_text = saved_state.capture_config.include.join("\n");
+        let capture_exclude_text = saved_state.capture_config.exclude.join("\n");
+        let capture_ports_text = saved_state.capture_config.ports().to_string();
         let toasts = Toasts::default().with_anchor(egui_notify::Anchor::BottomLeft);
 
         Self {
             saved_state,
             ui_message_tx,
             log_packets_tx,
+            capture_config_tx,
+            capture_overrides,
             tracing_reload_handle,
             toasts,
             power_tools_open: false,
             bug_report_open: false,
+            command_catalog_open: false,
+            command_catalog_unhandled_only: false,
+            command_catalog_by_count: false,
+            command_catalog_rx,
+            inspector_open: false,
+            inspector_open_tx,
+            inspector: Inspector::new(recent_commands_rx),
             capture_settings_open: false,
+            capture_devices: None,
+            capture_include_text,
+            capture_exclude_text,
+            capture_ports_text,
             optimizer_settings_open: false,
             optimizer_export_rx: None,
             optimizer_save_dialog: None,
             optimizer_save_path: None,
             optimizer_export_target: OptimizerExportTarget::None,
+            game_data_dialog: None,
+            replay_dialog: None,
+            pending_replay: replay,
+            replay_rx: None,
             restarting: false,
             state_rx,
             wish_url_rx,
@@ -242,6 +362,25 @@ impl eframe::App for IrminsulApp {
         if let Some(optimizer_save_dialog) = &mut self.optimizer_save_dialog {
             optimizer_save_dialog.update(ctx);
         }
+        if let Some(game_data_dialog) = &mut self.game_data_dialog {
+            game_data_dialog.update(ctx);
+            if let Some(path) = game_data_dialog.take_picked() {
+                self.saved_state.game_data_path = Some(path);
+            }
+        }
+        if let Some(replay_dialog) = &mut self.replay_dialog {
+            replay_dialog.update(ctx);
+            if let Some(path) = replay_dialog.take_picked() {
+                self.pending_replay = Some(path);
+            }
+        }
+        self.replay_packet_log();
+        if self.inspector_open {
+            self.inspector.show(ctx, &mut self.inspector_open);
+        }
+        self.inspector_open_tx.send_if_modified(|open| {
+            std::mem::replace(open, self.inspector_open) != self.inspector_open
+        });
 
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.with_layout(egui::Layout::top_down(egui::Align::LEFT), |ui| {
@@ -278,6 +417,15 @@ impl eframe::App for IrminsulApp {
                     }
                 }
 
+                if self.command_catalog_open {
+                    let modal = Modal::new(Id::new("Command Catalog")).show(ui.ctx(), |ui| {
+                        self.command_catalog_modal(ui);
+                    });
+                    if modal.should_close() {
+                        self.command_catalog_open = false;
+                    }
+                }
+
                 if self.bug_report_open {
                     let modal = Modal::new(Id::new("Bug Report")).show(ui.ctx(), |ui| {
                         self.bug_report_modal(ui);
@@ -444,15 +592,22 @@ impl IrminsulApp {
         confirmation_type: ConfirmationType,
     ) {
         let label = match confirmation_type {
-            ConfirmationType::Initial => "Irminsul needs to download initial data",
+            ConfirmationType::Initial => "Game data updates are available to download",
             ConfirmationType::Update => "New data available",
         };
         ui.label(label.to_string());
-        if ui.add(egui::Button::new("Download")).clicked() {
-            if let Err(e) = self.ui_message_tx.send(Message::DownloadAcknowledged) {
-                tracing::error!("Unable to send UI message{e}");
+        ui.horizontal(|ui| {
+            if ui.add(egui::Button::new("Download")).clicked() {
+                if let Err(e) = self.ui_message_tx.send(Message::DownloadAcknowledged) {
+                    tracing::error!("Unable to send UI message{e}");
+                }
             }
-        }
+            if ui.add(egui::Button::new("Skip")).clicked() {
+                if let Err(e) = self.ui_message_tx.send(Message::DownloadCanceled) {
+                    tracing::error!("Unable to send UI message{e}");
+                }
+            }
+        });
     }
 
     fn load_data_ui(&self, ui: &mut egui::Ui) {
@@ -502,6 +657,7 @@ impl IrminsulApp {
                         .clicked()
                     {
                         self.capture_settings_open = true;
+                        self.capture_devices = capture::list_devices().toast_error(self);
                     }
 
                     if app_state.capturing {
@@ -522,10 +678,120 @@ impl IrminsulApp {
             .num_columns(2)
             .min_col_width(0.)
             .show(ui, |ui| {
-                Self::data_state(ui, "Items", app_state.updated.items_updated);
-                Self::data_state(ui, "Characters", app_state.updated.characters_updated);
-                Self::data_state(ui, "Achievements", app_state.updated.achievements_updated);
+                for (category, label) in DATA_CATEGORIES {
+                    Self::data_state(ui, label, app_state.updated.get(category));
+                }
+                // Categories published by other handlers, once they have data.
+                for (category, updated) in app_state.updated.iter() {
+                    if !DATA_CATEGORIES.iter().any(|(known, _)| *known == category) {
+                        Self::data_state(ui, category, Some(updated));
+                    }
+                }
+                Self::capture_status_state(ui, &app_state.capture_status);
+                Self::key_status_state(ui, app_state.key_status);
+                Self::unmapped_ids_state(ui, &app_state.unmapped_ids);
             });
+        if app_state.capturing {
+            Self::capture_diagnostics_ui(ui, app_state);
+        }
+    }
+
+    fn capture_diagnostics_ui(ui: &mut egui::Ui, app_state: &AppState) {
+        let stats = &app_state.capture_stats;
+        egui::CollapsingHeader::new("Diagnostics")
+            .id_salt("capture_diagnostics")
+            .show(ui, |ui| {
+                ui.label(stats.diagnosis(app_state.key_status));
+                egui::Grid::new("capture_diagnostics_grid")
+                    .num_columns(2)
+                    .show(ui, |ui| {
+                        ui.label("Packets");
+                        ui.label(stats.packets.to_string());
+                        ui.end_row();
+                        for (port, packets) in &stats.packets_per_port {
+                            ui.label(format!("  Port {port}"));
+                            ui.label(packets.to_string());
+                            ui.end_row();
+                        }
+                        ui.label("Handshakes");
+                        ui.label(stats.handshakes.to_string());
+                        ui.end_row();
+                        ui.label("Undecoded packets").on_hover_text(
+                            "Packets without commands before a decryption key matched",
+                        );
+                        ui.label(stats.undecoded_packets.to_string());
+                        ui.end_row();
+                        ui.label("Commands decoded");
+                        ui.label(stats.commands.to_string());
+                        ui.end_row();
+                        ui.label("Dropped packets");
+                        ui.label(stats.dropped_packets.to_string());
+                        ui.end_row();
+                        ui.label("Queue high water").on_hover_text(
+                            "Most packets waiting in the capture and monitor queues",
+                        );
+                        ui.label(format!(
+                            "{} / {}",
+                            stats.backend_queue_high_water, stats.monitor_queue_high_water
+                        ));
+                        ui.end_row();
+                    });
+            });
+    }
+
+    fn capture_status_state(ui: &mut egui::Ui, capture_status: &CaptureStatus) {
+        let (icon, text, error) = match capture_status {
+            CaptureStatus::Ok => return,
+            CaptureStatus::Restarting(error) => (
+                egui_material_icons::icons::ICON_WARNING,
+                "Capture interrupted, restarting",
+                error,
+            ),
+            CaptureStatus::Failed(error) => (
+                egui_material_icons::icons::ICON_ERROR,
+                "Capture stopped",
+                error,
+            ),
+        };
+
+        ui.label(RichText::new(icon).color(WARNING_COLOR));
+        ui.label(text).on_hover_text(error);
+        ui.end_row();
+    }
+
+    fn key_status_state(ui: &mut egui::Ui, key_status: KeyStatus) {
+        if key_status != KeyStatus::NoMatch {
+            return;
+        }
+
+        ui.label(RichText::new(egui_material_icons::icons::ICON_KEY_OFF).color(WARNING_COLOR));
+        ui.label("No decryption key matched").on_hover_text(
+            "The game may have changed its keys.  Place an updated keys.json in Irminsul's \
+            storage directory (or pass --keys) and restart the game.",
+        );
+        ui.end_row();
+    }
+
+    fn unmapped_ids_state(ui: &mut egui::Ui, unmapped_ids: &UnmappedIds) {
+        if unmapped_ids.is_empty() {
+            return;
+        }
+
+        let details = unmapped_ids
+            .categories()
+            .filter(|(_, ids)| !ids.is_empty())
+            .map(|(category, ids)| {
+                let ids = ids.iter().map(|id| id.to_string()).collect::<Vec<_>>();
+                format!("{category}: {}", ids.join(", "))
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        ui.label(RichText::new(egui_material_icons::icons::ICON_WARNING).color(WARNING_COLOR));
+        ui.label(format!("{} unmapped game IDs", unmapped_ids.len()))
+            .on_hover_text(format!(
+                "These IDs are missing from Irminsul's game data and are left out of exports:\n{details}"
+            ));
+        ui.end_row();
     }
 
     fn data_state(ui: &mut egui::Ui, source: &str, last_updated: Option<Instant>) {
@@ -557,8 +823,8 @@ impl IrminsulApp {
                     }
 
                     ui.add_enabled_ui(
-                        app_state.updated.characters_updated.is_some()
-                            && app_state.updated.items_updated.is_some()
+                        app_state.updated.get(handlers::AVATARS).is_some()
+                            && app_state.updated.get(handlers::ITEMS).is_some()
                             && self.optimizer_export_rx.is_none(),
                         |ui| {
                             if ui
@@ -640,12 +906,22 @@ impl IrminsulApp {
         ui.set_width(300.0);
         ui.heading("Power Tools");
         ui.separator();
-        if ui
+        let mut log_settings_changed = ui
             .checkbox(&mut self.saved_state.log_raw_packets, "Log raw packets")
-            .changed()
-        {
-            let _ = self.log_packets_tx.send(self.saved_state.log_raw_packets);
-        };
+            .changed();
+        ui.add_enabled_ui(self.saved_state.log_raw_packets, |ui| {
+            log_settings_changed |= ui
+                .checkbox(
+                    &mut self.saved_state.decode_logged_packets,
+                    "Decode known packets to JSON",
+                )
+                .changed();
+        });
+        if log_settings_changed {
+            let _ = self
+                .log_packets_tx
+                .send(self.saved_state.packet_log_settings());
+        }
         let prev_level = self.saved_state.tracing_level;
         egui::ComboBox::from_label("Logging Level")
             .selected_text(format!("{}", self.saved_state.tracing_level))
@@ -676,6 +952,131 @@ impl IrminsulApp {
                 .set_filter(self.saved_state.tracing_level.get_filter());
         }
         ui.end_row();
+        ui.label("Game data file (applies on restart)");
+        ui.horizontal(|ui| {
+            let path = match &self.saved_state.game_data_path {
+                Some(path) => path.display().to_string(),
+                None => "Embedded".to_string(),
+            };
+            ui.label(path);
+            if ui.button("Browse").clicked() {
+                let mut game_data_dialog = FileDialog::new()
+                    .add_file_filter_extensions("Game data files", vec!["json", "gz"]);
+                game_data_dialog.pick_file();
+                self.game_data_dialog = Some(game_data_dialog);
+            }
+            if self.saved_state.game_data_path.is_some() && ui.button("Clear").clicked() {
+                self.saved_state.game_data_path = None;
+            }
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Command catalog").clicked() {
+                self.command_catalog_open = true;
+                ui.close();
+            }
+            if ui.button("Packet inspector").clicked() {
+                self.inspector_open = true;
+                ui.close();
+            }
+        });
+        ui.add_enabled_ui(self.replay_rx.is_none(), |ui| {
+            if ui.button("Replay packet log").clicked() {
+                let mut replay_dialog = FileDialog::new().add_file_filter_extensions(
+                    "Packet logs",
+                    vec![packet_log::LOG_EXTENSION, "bin"],
+                );
+                if let Ok(dir) = packet_log::packet_log_dir() {
+                    replay_dialog = replay_dialog.initial_directory(dir);
+                }
+                replay_dialog.pick_file();
+                self.replay_dialog = Some(replay_dialog);
+            }
+        });
+        let unmapped_ids = self.state_rx.borrow().unmapped_ids.clone();
+        ui.add_enabled_ui(!unmapped_ids.is_empty(), |ui| {
+            if ui.button("Save unmapped ID report").clicked()
+                && let Some(path) = save_unmapped_ids_report(&unmapped_ids).toast_error(self)
+            {
+                self.toasts
+                    .info(format!("Unmapped ID report saved to {}", path.display()));
+            }
+        });
+        ui.separator();
+        egui::Sides::new().show(
+            ui,
+            |_ui| {},
+            |ui| {
+                if ui.button("Ok").clicked() {
+                    ui.close()
+                }
+            },
+        );
+    }
+
+    fn command_catalog_modal(&mut self, ui: &mut egui::Ui) {
+        ui.set_width(640.0);
+        ui.heading("Command Catalog");
+        ui.separator();
+
+        let catalog = self.command_catalog_rx.borrow();
+        let total: u64 = catalog.values().map(|summary| summary.count).sum();
+        ui.label(format!(
+            "{} commands with {} different ids since capture was started",
+            total,
+            catalog.len()
+        ));
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.command_catalog_unhandled_only, "Only unhandled");
+            ui.checkbox(&mut self.command_catalog_by_count, "Sort by count");
+            if ui.button("Copy as CSV").clicked() {
+                ui.ctx().copy_text(command_catalog_csv(&catalog));
+                self.toasts.info("Command catalog copied to clipboard");
+            }
+        });
+
+        let mut entries = catalog
+            .iter()
+            .filter(|(_, summary)| {
+                !self.command_catalog_unhandled_only || summary.handled.is_none()
+            })
+            .collect::<Vec<_>>();
+        if self.command_catalog_by_count {
+            entries.sort_by_key(|(_, summary)| std::cmp::Reverse(summary.count));
+        }
+
+        egui::ScrollArea::vertical()
+            .max_height(400.0)
+            .show(ui, |ui| {
+                egui::Grid::new("command_catalog")
+                    .num_columns(7)
+                    .striped(true)
+                    .show(ui, |ui| {
+                        for header in [
+                            "Id",
+                            "Count",
+                            "Min size",
+                            "Max size",
+                            "First seen",
+                            "Last seen",
+                            "Handled",
+                        ] {
+                            ui.label(RichText::new(header).strong());
+                        }
+                        ui.end_row();
+
+                        for (id, summary) in entries {
+                            ui.label(id.to_string());
+                            ui.label(summary.count.to_string());
+                            ui.label(summary.min_bytes.to_string());
+                            ui.label(summary.max_bytes.to_string());
+                            ui.label(format_seen(summary.first_seen));
+                            ui.label(format_seen(summary.last_seen));
+                            ui.label(summary.handled.unwrap_or(""));
+                            ui.end_row();
+                        }
+                    });
+            });
+
         ui.separator();
         egui::Sides::new().show(
             ui,
@@ -718,12 +1119,94 @@ impl IrminsulApp {
 
     fn capture_settings_modal(&mut self, ui: &mut egui::Ui) {
         ui.set_width(300.0);
-        ui.heading("Genshin Optimizer Settings");
+        ui.heading("Capture Settings");
         ui.separator();
         ui.checkbox(
             &mut self.saved_state.auto_start_capture,
             "Start capture on Irminsul launch",
         );
+
+        let prev_config = self.saved_state.capture_config.clone();
+        let config = &mut self.saved_state.capture_config;
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.label("Game server ports");
+            let response = ui.text_edit_singleline(&mut self.capture_ports_text);
+            match self.capture_ports_text.parse::<PortRanges>() {
+                Ok(ports) => {
+                    if response.changed() {
+                        config.ports = (ports != PortRanges::default()).then_some(ports);
+                    }
+                }
+                Err(e) => {
+                    ui.label(
+                        RichText::new(egui_material_icons::icons::ICON_ERROR).color(WARNING_COLOR),
+                    )
+                    .on_hover_text(e.to_string());
+                }
+            }
+        });
+        ui.checkbox(
+            &mut config.auto_detect_port,
+            "Detect the server port from the login handshake",
+        );
+        if let Some(devices) = &self.capture_devices
+            && !devices.is_empty()
+        {
+            ui.separator();
+            ui.label("Capture devices (none selected uses all connected devices)");
+            egui::ScrollArea::vertical()
+                .max_height(120.)
+                .show(ui, |ui| {
+                    for device in devices {
+                        let mut selected = config.devices.contains(&device.name);
+                        let label = match &device.desc {
+                            Some(desc) => format!("{} ({desc})", device.name),
+                            None => device.name.clone(),
+                        };
+                        if ui.checkbox(&mut selected, label).changed() {
+                            if selected {
+                                config.devices.push(device.name.clone());
+                            } else {
+                                config.devices.retain(|name| name != &device.name);
+                            }
+                        }
+                    }
+                });
+        }
+
+        ui.collapsing("Advanced", |ui| {
+            ui.label("Include devices matching (one regex per line)");
+            if ui
+                .text_edit_multiline(&mut self.capture_include_text)
+                .changed()
+            {
+                config.include = patterns_from_text(&self.capture_include_text);
+            }
+            ui.label("Exclude devices matching (one regex per line)");
+            if ui
+                .text_edit_multiline(&mut self.capture_exclude_text)
+                .changed()
+            {
+                config.exclude = patterns_from_text(&self.capture_exclude_text);
+            }
+            ui.label("Capture filter (BPF, empty uses the default)");
+            let mut filter = config.filter.clone().unwrap_or_default();
+            if ui.text_edit_singleline(&mut filter).changed() {
+                config.filter = (!filter.trim().is_empty()).then_some(filter);
+            }
+        });
+
+        if prev_config != self.saved_state.capture_config {
+            let config = self
+                .saved_state
+                .capture_config
+                .clone()
+                .with_overrides(self.capture_overrides.clone());
+            let _ = self.capture_config_tx.send(config);
+        }
+
         ui.separator();
         egui::Sides::new().show(
             ui,
@@ -856,6 +1339,33 @@ impl IrminsulApp {
         );
     }
 
+    /// Start a pending replay once the monitor is running and report the
+    /// outcome of a finished one.
+    fn replay_packet_log(&mut self) {
+        if self.replay_rx.is_none()
+            && matches!(self.state_rx.borrow().state, State::Main)
+            && let Some(path) = self.pending_replay.take()
+        {
+            let (tx, rx) = oneshot::channel();
+            let _ = self.ui_message_tx.send(Message::ReplayPacketLog(path, tx));
+            self.replay_rx = Some(rx);
+        }
+
+        let Some(rx) = &mut self.replay_rx else {
+            return;
+        };
+        let result = match rx.try_recv() {
+            Ok(result) => result,
+            Err(oneshot::error::TryRecvError::Empty) => return,
+            Err(oneshot::error::TryRecvError::Closed) => Err(anyhow!("Packet log replay failed")),
+        };
+        self.replay_rx = None;
+        if let Some(count) = result.toast_error(self) {
+            self.toasts
+                .info(format!("Replayed {count} commands from packet log"));
+        }
+    }
+
     fn optimizer_handle_export(&mut self, ui: &mut egui::Ui) -> Result<()> {
         let Some(rx) = self.optimizer_export_rx.take() else {
             return Ok(());
@@ -895,6 +1405,8 @@ impl IrminsulApp {
         let file = File::create(&path).with_context(|| format!("Unable to open file {path:?}"))?;
         let mut writer = BufWriter::new(file);
         writer.write_all(json.as_bytes())?;
+        #[cfg(unix)]
+        crate::admin::chown_to_sudo_user(&path);
 
         self.toasts.info("Genshin Optimizer data saved to file");
         Ok(())
@@ -909,3 +1421,48 @@ impl IrminsulApp {
         ui.label(RichText::new(name).size(18.));
     }
 }
+
+fn patterns_from_text(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+fn format_seen(seen: SystemTime) -> String {
+    DateTime::<Local>::from(seen)
+        .format("%H:%M:%S%.3f")
+        .to_string()
+}
+
+fn command_catalog_csv(catalog: &CommandCatalog) -> String {
+    let mut csv =
+        "id,count,total_bytes,min_bytes,max_bytes,first_seen,last_seen,handled\n".to_string();
+    for (id, summary) in catalog {
+        csv.push_str(&format!(
+            "{id},{},{},{},{},{},{},{}\n",
+            summary.count,
+            summary.total_bytes,
+            summary.min_bytes,
+            summary.max_bytes,
+            DateTime::<Local>::from(summary.first_seen).to_rfc3339(),
+            DateTime::<Local>::from(summary.last_seen).to_rfc3339(),
+            summary.handled.unwrap_or(""),
+        ));
+    }
+    csv
+}
+
+fn save_unmapped_ids_report(unmapped_ids: &UnmappedIds) -> Result<PathBuf> {
+    let mut path = eframe::storage_dir(APP_ID).context("Storage dir not found")?;
+    std::fs::create_dir_all(&path)?;
+    path.push("unmapped_ids.json");
+
+    let file = File::create(&path).with_context(|| format!("Unable to open file {path:?}"))?;
+    serde_json::to_writer_pretty(BufWriter::new(file), unmapped_ids)?;
+    #[cfg(unix)]
+    crate::admin::chown_to_sudo_user(&path);
+
+    Ok(path)
+}
@@ -1,22 +1,231 @@
+#[cfg(target_os = "linux")]
+mod af_packet_backend;
+
 #[cfg(feature = "pcap")]
 mod pcap_backend;
 
+#[cfg(target_os = "linux")]
+pub mod helper;
+
 #[cfg(windows)]
 mod pktmon_backend;
 
+mod mirror_backend;
+mod stdin_backend;
+
+pub mod dedup;
+pub mod link;
+pub mod parse;
+pub mod pcap_stream;
+pub mod queue;
+pub mod reassembly;
+
 use std::fmt::{Debug, Display};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
 
-use anyhow::Error;
+use anyhow::{Error, anyhow};
 use async_trait::async_trait;
 use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::capture::queue::QueueStats;
 
 pub const PORT_RANGE: (u16, u16) = (22101, 22102);
 
+/// Matches IP fragments that can't be filtered by port: IPv4 UDP fragments
+/// past the first, and every IPv6 fragment.
+const FRAGMENT_FILTER: &str = "(ip[9] == 17 and ip[6:2] & 0x1fff != 0) or (ip6 and ip6[6] == 44)";
+
+/// Port the mirror backend listens on by default.
+pub const DEFAULT_MIRROR_PORT: u16 = 9011;
+
+/// A set of UDP port ranges the game server may use.
+///
+/// Parsed from and displayed as a comma separated list of ports and
+/// inclusive ranges, e.g. `22101-22102,23301`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PortRanges(Vec<(u16, u16)>);
+
+impl PortRanges {
+    pub fn contains(&self, port: u16) -> bool {
+        self.0
+            .iter()
+            .any(|(start, end)| (*start..=*end).contains(&port))
+    }
+
+    pub fn ranges(&self) -> &[(u16, u16)] {
+        &self.0
+    }
+
+    /// Add `port` to the set if it isn't already covered.
+    pub fn insert(&mut self, port: u16) {
+        if !self.contains(port) {
+            self.0.push((port, port));
+        }
+    }
+
+    /// BPF expression matching UDP traffic on any of the ranges, plus any IP
+    /// fragments that may belong to it.
+    pub fn bpf_filter(&self) -> String {
+        let ports = self
+            .0
+            .iter()
+            .map(|(start, end)| {
+                if start == end {
+                    format!("port {start}")
+                } else {
+                    format!("portrange {start}-{end}")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" or ");
+        format!("(udp and ({ports})) or {FRAGMENT_FILTER}")
+    }
+}
+
+impl Default for PortRanges {
+    fn default() -> Self {
+        Self(vec![PORT_RANGE])
+    }
+}
+
+impl Display for PortRanges {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (start, end)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            if start == end {
+                write!(f, "{start}")?;
+            } else {
+                write!(f, "{start}-{end}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PortRanges {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let ranges = s
+            .split(',')
+            .map(str::trim)
+            .filter(|range| !range.is_empty())
+            .map(|range| {
+                let (start, end) = range.split_once('-').unwrap_or((range, range));
+                let start: u16 = start.trim().parse()?;
+                let end: u16 = end.trim().parse()?;
+                if start > end {
+                    return Err(anyhow!("Invalid port range {range}"));
+                }
+                Ok((start, end))
+            })
+            .collect::<std::result::Result<Vec<_>, Error>>()?;
+
+        if ranges.is_empty() {
+            return Err(anyhow!("No ports specified"));
+        }
+        Ok(Self(ranges))
+    }
+}
+
+impl TryFrom<String> for PortRanges {
+    type Error = Error;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<PortRanges> for String {
+    fn from(value: PortRanges) -> Self {
+        value.to_string()
+    }
+}
+
+/// Where the mirror backend listens for forwarded packets.
+///
+/// Parsed from and displayed as `udp://<addr>:<port>` or
+/// `tcp://<addr>:<port>`.  Addresses without a scheme use UDP.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum MirrorAddress {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+}
+
+/// Forwarded packets aren't authenticated, so only local senders are
+/// accepted unless another address is given explicitly.
+impl Default for MirrorAddress {
+    fn default() -> Self {
+        Self::Udp(SocketAddr::new(
+            Ipv4Addr::LOCALHOST.into(),
+            DEFAULT_MIRROR_PORT,
+        ))
+    }
+}
+
+impl Display for MirrorAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MirrorAddress::Udp(addr) => write!(f, "udp://{addr}"),
+            MirrorAddress::Tcp(addr) => write!(f, "tcp://{addr}"),
+        }
+    }
+}
+
+impl FromStr for MirrorAddress {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (scheme, addr) = s.split_once("://").unwrap_or(("udp", s));
+        let addr = addr
+            .parse()
+            .map_err(|e| anyhow!("Invalid mirror address {addr}: {e}"))?;
+        match scheme {
+            "udp" => Ok(Self::Udp(addr)),
+            "tcp" => Ok(Self::Tcp(addr)),
+            _ => Err(anyhow!("Unknown mirror protocol {scheme}")),
+        }
+    }
+}
+
+impl TryFrom<String> for MirrorAddress {
+    type Error = Error;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<MirrorAddress> for String {
+    fn from(value: MirrorAddress) -> Self {
+        value.to_string()
+    }
+}
+
 #[derive(Debug)]
 #[allow(dead_code)]
 pub enum CaptureError {
     Filter(Error),
-    Capture { has_captured: bool, error: Error },
+    Capture {
+        has_captured: bool,
+        error: Error,
+    },
+    /// Capture privileges couldn't be obtained, e.g. the user dismissed the
+    /// authorization prompt.
+    Permission(Error),
+    /// The backend can't capture with this build, operating system or
+    /// configuration, e.g. no device matches the device selection.
+    Unsupported(Error),
     CaptureClosed,
     ChannelClosed,
 }
@@ -33,6 +242,8 @@ impl Display for CaptureError {
                 "Capture error (has_captured = {}): {}",
                 has_captured, error
             ),
+            CaptureError::Permission(e) => write!(f, "Permission error: {}", e),
+            CaptureError::Unsupported(e) => write!(f, "Unsupported capture: {}", e),
             CaptureError::CaptureClosed => write!(f, "Capture closed"),
             CaptureError::ChannelClosed => write!(f, "Channel closed"),
         }
@@ -41,51 +252,369 @@ impl Display for CaptureError {
 
 pub type Result<T> = std::result::Result<T, CaptureError>;
 
+/// Which way a packet travels relative to the game server.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Direction {
+    #[default]
+    Unknown,
+    ClientToServer,
+    ServerToClient,
+}
+
+impl Display for Direction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Direction::Unknown => write!(f, "unknown"),
+            Direction::ClientToServer => write!(f, "client->server"),
+            Direction::ServerToClient => write!(f, "server->client"),
+        }
+    }
+}
+
+/// A captured Ethernet frame along with what the backend knows about it.
+#[derive(Clone, Debug)]
+pub struct CapturedPacket {
+    /// When the packet was captured, as reported by the backend if possible.
+    pub timestamp: SystemTime,
+    /// Name of the interface the packet was captured on, if known.
+    pub interface: Option<String>,
+    /// UDP source and destination, if the frame holds a UDP datagram.
+    pub src: Option<SocketAddr>,
+    pub dst: Option<SocketAddr>,
+    /// Filled in by `classify` once the game server ports are known.
+    pub direction: Direction,
+    /// The Ethernet frame, the format the sniffer expects.
+    pub data: Vec<u8>,
+}
+
+impl CapturedPacket {
+    pub fn new(data: Vec<u8>, timestamp: SystemTime, interface: Option<String>) -> Self {
+        let (src, dst) = match parse::parse_ethernet_udp(&data) {
+            Some(datagram) => (Some(datagram.src), Some(datagram.dst)),
+            None => (None, None),
+        };
+
+        Self {
+            timestamp,
+            interface,
+            src,
+            dst,
+            direction: Direction::Unknown,
+            data,
+        }
+    }
+
+    /// Determine the packet's direction from the game server ports.
+    pub fn classify(&mut self, ports: &PortRanges) {
+        let is_server =
+            |addr: Option<SocketAddr>| addr.is_some_and(|addr| ports.contains(addr.port()));
+        self.direction = if is_server(self.src) {
+            Direction::ServerToClient
+        } else if is_server(self.dst) {
+            Direction::ClientToServer
+        } else {
+            Direction::Unknown
+        };
+    }
+}
+
 #[async_trait]
 pub trait CaptureBackend: Send {
-    async fn next_packet(&mut self) -> Result<Vec<u8>>;
+    async fn next_packet(&mut self) -> Result<CapturedPacket>;
+}
+
+/// User selectable capture options.  Backends ignore options they can't
+/// support; pktmon always captures on every interface with its own filter.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CaptureConfig {
+    /// Names of the devices to capture on.  When empty, every connected
+    /// device is used.
+    #[serde(default)]
+    pub devices: Vec<String>,
+
+    /// Regexes matched against device names and descriptions.  When not
+    /// empty, only devices matching one of them are used.
+    #[serde(default)]
+    pub include: Vec<String>,
+
+    /// Regexes matched against device names and descriptions.  Matching
+    /// devices are never used.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+
+    /// BPF expression replacing the default game port filter.
+    #[serde(default)]
+    pub filter: Option<String>,
+
+    /// Game server ports.  `None` uses `PORT_RANGE`.
+    #[serde(default)]
+    pub ports: Option<PortRanges>,
+
+    /// Capture all UDP traffic and lock on to the server port of the first
+    /// handshake seen.
+    #[serde(default)]
+    pub auto_detect_port: bool,
+
+    /// Address the mirror backend listens on.  `None` uses
+    /// `MirrorAddress::default`.
+    #[serde(default)]
+    pub mirror: Option<MirrorAddress>,
+}
+
+/// Capture options that take precedence over the saved `CaptureConfig`,
+/// e.g. from the command line.  Unset options keep their saved value.
+#[derive(Clone, Debug, Default)]
+pub struct CaptureOverrides {
+    pub devices: Vec<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub filter: Option<String>,
+    pub ports: Option<PortRanges>,
+    pub auto_detect_port: Option<bool>,
+    pub mirror: Option<MirrorAddress>,
+}
+
+impl CaptureConfig {
+    /// Replace any options set in `overrides`.
+    pub fn with_overrides(mut self, overrides: CaptureOverrides) -> Self {
+        if !overrides.devices.is_empty() {
+            self.devices = overrides.devices;
+        }
+        if !overrides.include.is_empty() {
+            self.include = overrides.include;
+        }
+        if !overrides.exclude.is_empty() {
+            self.exclude = overrides.exclude;
+        }
+        if overrides.filter.is_some() {
+            self.filter = overrides.filter;
+        }
+        if overrides.ports.is_some() {
+            self.ports = overrides.ports;
+        }
+        if let Some(auto_detect_port) = overrides.auto_detect_port {
+            self.auto_detect_port = auto_detect_port;
+        }
+        if overrides.mirror.is_some() {
+            self.mirror = overrides.mirror;
+        }
+        self
+    }
+
+    pub fn ports(&self) -> PortRanges {
+        self.ports.clone().unwrap_or_default()
+    }
+
+    /// The BPF filter backends should capture with.
+    pub fn bpf_filter(&self) -> String {
+        if let Some(filter) = &self.filter {
+            filter.clone()
+        } else if self.auto_detect_port {
+            format!("udp or {FRAGMENT_FILTER}")
+        } else {
+            self.ports().bpf_filter()
+        }
+    }
+}
+
+/// A capture device as reported by the backend.
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub desc: Option<String>,
 }
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq, ValueEnum)]
+/// List the devices available for capture.  Only the pcap and AF_PACKET
+/// backends support selecting devices.
+pub fn list_devices() -> Result<Vec<DeviceInfo>> {
+    #[cfg(feature = "pcap")]
+    {
+        pcap_backend::PcapBackend::list_devices()
+    }
+    #[cfg(all(not(feature = "pcap"), target_os = "linux"))]
+    {
+        af_packet_backend::AfPacketBackend::list_devices()
+    }
+    #[cfg(all(not(feature = "pcap"), not(target_os = "linux")))]
+    {
+        Ok(Vec::new())
+    }
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Serialize, ValueEnum)]
 #[allow(unused)]
 pub enum BackendType {
     Pktmon,
     Pcap,
+    AfPacket,
+    Mirror,
+    Stdin,
 }
 
 #[cfg(windows)]
 pub const DEFAULT_CAPTURE_BACKEND_TYPE: BackendType = BackendType::Pktmon;
-#[cfg(not(windows))]
+#[cfg(all(target_os = "linux", not(feature = "pcap")))]
+pub const DEFAULT_CAPTURE_BACKEND_TYPE: BackendType = BackendType::AfPacket;
+#[cfg(not(any(windows, all(target_os = "linux", not(feature = "pcap")))))]
 pub const DEFAULT_CAPTURE_BACKEND_TYPE: BackendType = BackendType::Pcap;
 
-pub fn create_capture(backend: BackendType) -> Result<Box<dyn CaptureBackend>> {
+/// Set once root privileges have been dropped.
+#[cfg(target_os = "linux")]
+static PRIVILEGES_DROPPED: AtomicBool = AtomicBool::new(false);
+
+/// Once the capture devices are open there's no reason to keep running as
+/// root.  Restarting capture afterwards goes through the capture helper, or
+/// needs Irminsul to be started again if there is none.
+#[cfg(target_os = "linux")]
+fn drop_privileges() {
+    match crate::admin::drop_privileges() {
+        Ok(true) => {
+            PRIVILEGES_DROPPED.store(true, Ordering::Relaxed);
+            if !helper::available() {
+                tracing::warn!(
+                    "Dropped root privileges without pkexec for the capture helper, restarting \
+                     capture will need Irminsul to be started again"
+                );
+            }
+        }
+        Ok(false) => {}
+        Err(e) => tracing::error!("Unable to drop root privileges: {e:#}"),
+    }
+}
+
+/// Create a capture backend.  Backends that queue packets internally record
+/// drops and fill levels in `queue_stats`.
+pub fn create_capture(
+    backend: BackendType,
+    config: &CaptureConfig,
+    queue_stats: Arc<QueueStats>,
+) -> Result<Box<dyn CaptureBackend>> {
+    // Without capture privileges of our own, let the helper capture for us.
+    #[cfg(target_os = "linux")]
+    if matches!(backend, BackendType::Pcap | BackendType::AfPacket) && helper::needed() {
+        return Ok(Box::new(helper::HelperBackend::new(
+            backend,
+            config,
+            queue_stats,
+        )?));
+    }
+    #[cfg(target_os = "linux")]
+    if matches!(backend, BackendType::Pcap | BackendType::AfPacket)
+        && PRIVILEGES_DROPPED.load(Ordering::Relaxed)
+        && !crate::admin::has_capture_privileges()
+    {
+        return Err(CaptureError::Permission(anyhow!(
+            "Root privileges were dropped once capture started, start Irminsul again to restart \
+             capture"
+        )));
+    }
+
     match backend {
         BackendType::Pktmon => {
             #[cfg(windows)]
             {
-                Ok(Box::new(pktmon_backend::PktmonBackend::new()?))
+                Ok(Box::new(pktmon_backend::PktmonBackend::new(config)?))
             }
             #[cfg(not(windows))]
             {
-                Err(CaptureError::Capture {
-                    has_captured: false,
-                    error: anyhow::anyhow!("Pktmon capture not supported on this operating system"),
-                })
+                Err(CaptureError::Unsupported(anyhow::anyhow!(
+                    "Pktmon capture not supported on this operating system"
+                )))
             }
         }
 
         BackendType::Pcap => {
             #[cfg(feature = "pcap")]
             {
-                Ok(Box::new(pcap_backend::PcapBackend::new()?))
+                let capture = pcap_backend::PcapBackend::new(config, queue_stats)?;
+                #[cfg(target_os = "linux")]
+                drop_privileges();
+                Ok(Box::new(capture))
             }
             #[cfg(not(feature = "pcap"))]
             {
-                Err(CaptureError::Capture {
-                    has_captured: false,
-                    error: anyhow::anyhow!("Pktmon capture not supported on this build"),
-                })
+                let _ = config;
+                Err(CaptureError::Unsupported(anyhow::anyhow!(
+                    "Pktmon capture not supported on this build"
+                )))
             }
         }
+
+        BackendType::AfPacket => {
+            #[cfg(target_os = "linux")]
+            {
+                let capture = af_packet_backend::AfPacketBackend::new(config, queue_stats)?;
+                drop_privileges();
+                Ok(Box::new(capture))
+            }
+            #[cfg(not(target_os = "linux"))]
+            {
+                Err(CaptureError::Unsupported(anyhow::anyhow!(
+                    "AF_PACKET capture not supported on this operating system"
+                )))
+            }
+        }
+
+        BackendType::Mirror => Ok(Box::new(mirror_backend::MirrorBackend::new(
+            config,
+            queue_stats,
+        )?)),
+
+        BackendType::Stdin => Ok(Box::new(stdin_backend::StdinBackend::new(queue_stats)?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_port_ranges() {
+        let ports: PortRanges = "22101-22102, 23301 ,".parse().unwrap();
+        assert_eq!(ports.ranges(), &[(22101, 22102), (23301, 23301)]);
+        assert_eq!(ports.to_string(), "22101-22102,23301");
+        assert_eq!(ports.to_string().parse::<PortRanges>().unwrap(), ports);
+
+        let ports: PortRanges = " 1 - 65535 ".parse().unwrap();
+        assert_eq!(ports.ranges(), &[(1, 65535)]);
+    }
+
+    #[test]
+    fn rejects_invalid_port_ranges() {
+        for ports in [
+            "",
+            " , ",
+            "abc",
+            "70000",
+            "-5",
+            "5-",
+            "5-3",
+            "1-2-3",
+            "22101;22102",
+        ] {
+            assert!(ports.parse::<PortRanges>().is_err(), "{ports:?}");
+        }
+    }
+
+    #[test]
+    fn inserts_ports() {
+        let mut ports = PortRanges::default();
+        assert!(ports.contains(22102));
+        assert!(!ports.contains(22103));
+
+        ports.insert(22102);
+        assert_eq!(ports.ranges(), &[PORT_RANGE]);
+        ports.insert(22103);
+        assert!(ports.contains(22103));
+        assert_eq!(ports.to_string(), "22101-22102,22103");
+    }
+
+    #[test]
+    fn builds_bpf_filters() {
+        let ports: PortRanges = "22101-22102,23301".parse().unwrap();
+        assert_eq!(
+            ports.bpf_filter(),
+            format!("(udp and (portrange 22101-22102 or port 23301)) or {FRAGMENT_FILTER}")
+        );
     }
 }
@@ -0,0 +1,577 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use regex::Regex;
+use tokio::sync::mpsc::Receiver;
+
+use crate::capture::link::{self, LinkType};
+use crate::capture::queue::{self, BACKEND_QUEUE_LEN, PacketSender, QueueStats};
+use crate::capture::{
+    CaptureBackend, CaptureConfig, CaptureError, CapturedPacket, DeviceInfo, PortRanges, Result,
+};
+
+/// Large enough for any datagram the game sends, reassembled or not.
+const RECV_BUFFER_LEN: usize = 65536;
+/// Kernel side socket buffer, sized to ride out short stalls of the loop.
+const SOCKET_BUFFER_LEN: libc::c_int = 4 * 1024 * 1024;
+
+// Classic BPF opcodes from linux/filter.h.
+const BPF_LDB_ABS: u16 = 0x30;
+const BPF_LDH_ABS: u16 = 0x28;
+const BPF_LDH_IND: u16 = 0x48;
+const BPF_LDXB_MSH: u16 = 0xb1;
+const BPF_AND_K: u16 = 0x54;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_JGT_K: u16 = 0x25;
+const BPF_JGE_K: u16 = 0x35;
+const BPF_JSET_K: u16 = 0x45;
+const BPF_RET_K: u16 = 0x06;
+
+/// Offset of the ancillary load of the packet's ethertype, `SKF_AD_OFF +
+/// SKF_AD_PROTOCOL`.
+const SKF_AD_PROTOCOL: u32 = -0x1000i32 as u32;
+const ETH_P_IP: u32 = 0x0800;
+const ETH_P_IPV6: u32 = 0x86dd;
+
+#[derive(Clone, Copy)]
+enum Jump {
+    Next,
+    Skip(usize),
+    Accept,
+    Drop,
+}
+
+struct Insn {
+    code: u16,
+    k: u32,
+    jt: Jump,
+    jf: Jump,
+}
+
+impl Insn {
+    fn stmt(code: u16, k: u32) -> Self {
+        Self {
+            code,
+            k,
+            jt: Jump::Next,
+            jf: Jump::Next,
+        }
+    }
+
+    fn jump(code: u16, k: u32, jt: Jump, jf: Jump) -> Self {
+        Self { code, k, jt, jf }
+    }
+}
+
+/// Accept if the port in the accumulator is in `ports`, otherwise fall
+/// through.
+fn port_checks(program: &mut Vec<Insn>, ports: &PortRanges) {
+    for (start, end) in ports.ranges() {
+        if start == end {
+            program.push(Insn::jump(
+                BPF_JEQ_K,
+                (*start).into(),
+                Jump::Accept,
+                Jump::Next,
+            ));
+        } else {
+            program.push(Insn::jump(
+                BPF_JGE_K,
+                (*start).into(),
+                Jump::Next,
+                Jump::Skip(1),
+            ));
+            program.push(Insn::jump(
+                BPF_JGT_K,
+                (*end).into(),
+                Jump::Next,
+                Jump::Accept,
+            ));
+        }
+    }
+}
+
+/// Build a kernel filter for cooked (`SOCK_DGRAM`) sockets, where packets
+/// start at the network header.  It matches the same traffic as
+/// `CaptureConfig::bpf_filter`: UDP on `ports` (or any UDP if `None`) plus IP
+/// fragments that can't be matched by port.  The socket receives every
+/// protocol, so IP is recognized by the packet's ethertype rather than its
+/// first byte.
+fn kernel_filter(ports: Option<&PortRanges>) -> Option<Vec<libc::sock_filter>> {
+    let mut ipv6 = vec![
+        Insn::stmt(BPF_LDB_ABS, 6),
+        Insn::jump(BPF_JEQ_K, 44, Jump::Accept, Jump::Next),
+    ];
+    match ports {
+        Some(ports) => {
+            ipv6.push(Insn::jump(BPF_JEQ_K, 17, Jump::Next, Jump::Drop));
+            ipv6.push(Insn::stmt(BPF_LDH_ABS, 40));
+            port_checks(&mut ipv6, ports);
+            ipv6.push(Insn::stmt(BPF_LDH_ABS, 42));
+            port_checks(&mut ipv6, ports);
+            ipv6.push(Insn::stmt(BPF_RET_K, 0));
+        }
+        None => ipv6.push(Insn::jump(BPF_JEQ_K, 17, Jump::Accept, Jump::Drop)),
+    }
+
+    let mut program = vec![
+        Insn::stmt(BPF_LDH_ABS, SKF_AD_PROTOCOL),
+        Insn::jump(BPF_JEQ_K, ETH_P_IPV6, Jump::Next, Jump::Skip(ipv6.len())),
+    ];
+    program.append(&mut ipv6);
+    program.push(Insn::jump(BPF_JEQ_K, ETH_P_IP, Jump::Next, Jump::Drop));
+    program.push(Insn::stmt(BPF_LDB_ABS, 9));
+    match ports {
+        Some(ports) => {
+            program.push(Insn::jump(BPF_JEQ_K, 17, Jump::Next, Jump::Drop));
+            // Fragments past the first carry no UDP header.
+            program.push(Insn::stmt(BPF_LDH_ABS, 6));
+            program.push(Insn::jump(BPF_JSET_K, 0x1fff, Jump::Accept, Jump::Next));
+            program.push(Insn::stmt(BPF_LDXB_MSH, 0));
+            program.push(Insn::stmt(BPF_LDH_IND, 0));
+            port_checks(&mut program, ports);
+            program.push(Insn::stmt(BPF_LDH_IND, 2));
+            port_checks(&mut program, ports);
+            program.push(Insn::stmt(BPF_RET_K, 0));
+        }
+        None => program.push(Insn::jump(BPF_JEQ_K, 17, Jump::Accept, Jump::Drop)),
+    }
+
+    let accept = program.len();
+    let drop = accept + 1;
+    program.push(Insn::stmt(BPF_RET_K, RECV_BUFFER_LEN as u32));
+    program.push(Insn::stmt(BPF_RET_K, 0));
+
+    program
+        .iter()
+        .enumerate()
+        .map(|(i, insn)| {
+            let offset = |jump| -> Option<u8> {
+                match jump {
+                    Jump::Next => Some(0),
+                    Jump::Skip(n) => n.try_into().ok(),
+                    Jump::Accept => (accept - i - 1).try_into().ok(),
+                    Jump::Drop => (drop - i - 1).try_into().ok(),
+                }
+            };
+            Some(libc::sock_filter {
+                code: insn.code,
+                jt: offset(insn.jt)?,
+                jf: offset(insn.jf)?,
+                k: insn.k,
+            })
+        })
+        .collect()
+}
+
+fn last_os_error() -> CaptureError {
+    CaptureError::Capture {
+        has_captured: false,
+        error: io::Error::last_os_error().into(),
+    }
+}
+
+/// When the kernel received the packet `message` was read from, from its
+/// `SO_TIMESTAMP` control message.
+fn kernel_timestamp(message: &libc::msghdr) -> Option<SystemTime> {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(message) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if header.cmsg_level == libc::SOL_SOCKET && header.cmsg_type == libc::SCM_TIMESTAMP {
+            let time: libc::timeval =
+                unsafe { std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast()) };
+            return Some(
+                UNIX_EPOCH
+                    + Duration::from_secs(time.tv_sec.try_into().ok()?)
+                    + Duration::from_micros(time.tv_usec.try_into().ok()?),
+            );
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(message, cmsg) };
+    }
+    None
+}
+
+fn set_socket_option<T>(socket: &OwnedFd, option: libc::c_int, value: &T) -> Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            (value as *const T).cast(),
+            size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        return Err(last_os_error());
+    }
+    Ok(())
+}
+
+/// Network interfaces by index.
+fn interfaces() -> Result<HashMap<i32, String>> {
+    let entries = std::fs::read_dir("/sys/class/net").map_err(|e| CaptureError::Capture {
+        has_captured: false,
+        error: e.into(),
+    })?;
+
+    Ok(entries
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            let c_name = CString::new(name.as_str()).ok()?;
+            let index = unsafe { libc::if_nametoindex(c_name.as_ptr()) };
+            (index != 0).then_some((index as i32, name))
+        })
+        .collect())
+}
+
+pub struct AfPacketBackend {
+    packet_rx: Receiver<Result<CapturedPacket>>,
+}
+
+impl AfPacketBackend {
+    pub fn list_devices() -> Result<Vec<DeviceInfo>> {
+        let mut devices: Vec<_> = interfaces()?
+            .into_values()
+            .map(|name| DeviceInfo { name, desc: None })
+            .collect();
+        devices.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(devices)
+    }
+
+    /// The interfaces to capture on, or `None` for all of them.
+    fn selected_interfaces(
+        config: &CaptureConfig,
+        interfaces: &HashMap<i32, String>,
+    ) -> Result<Option<Vec<i32>>> {
+        if config.devices.is_empty() && config.include.is_empty() && config.exclude.is_empty() {
+            return Ok(None);
+        }
+
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|e| CaptureError::Filter(e.into()))
+        };
+        let include = compile(&config.include)?;
+        let exclude = compile(&config.exclude)?;
+
+        let selected: Vec<_> = interfaces
+            .iter()
+            .filter(|(_, name)| {
+                (config.devices.is_empty() || config.devices.contains(name))
+                    && (include.is_empty() || include.iter().any(|re| re.is_match(name)))
+                    && !exclude.iter().any(|re| re.is_match(name))
+            })
+            .map(|(index, _)| *index)
+            .collect();
+
+        if selected.is_empty() {
+            return Err(CaptureError::Unsupported(anyhow!(
+                "No capture device available"
+            )));
+        }
+        Ok(Some(selected))
+    }
+
+    pub fn new(config: &CaptureConfig, queue_stats: Arc<QueueStats>) -> Result<Self> {
+        if config.filter.is_some() {
+            return Err(CaptureError::Filter(anyhow!(
+                "Custom capture filters are only supported by the pcap backend"
+            )));
+        }
+
+        let interfaces = interfaces()?;
+        let selected = Self::selected_interfaces(config, &interfaces)?;
+        match &selected {
+            Some(selected) => {
+                for index in selected {
+                    tracing::info!("Capturing on {}", interfaces[index]);
+                }
+            }
+            None => tracing::info!("Capturing on all {} interfaces", interfaces.len()),
+        }
+
+        let ports = (!config.auto_detect_port).then(|| config.ports());
+        let mut filter = kernel_filter(ports.as_ref()).ok_or_else(|| {
+            CaptureError::Filter(anyhow!("Too many port ranges for the kernel filter"))
+        })?;
+
+        // Open the socket without a protocol so it receives nothing until the
+        // filter is attached and it is bound below.
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(last_os_error());
+        }
+        let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let program = libc::sock_fprog {
+            len: filter.len() as libc::c_ushort,
+            filter: filter.as_mut_ptr(),
+        };
+        set_socket_option(&socket, libc::SO_ATTACH_FILTER, &program)
+            .map_err(|e| CaptureError::Filter(anyhow!("{e}")))?;
+        set_socket_option(&socket, libc::SO_RCVBUF, &SOCKET_BUFFER_LEN)?;
+        set_socket_option(&socket, libc::SO_TIMESTAMP, &(1 as libc::c_int))?;
+        // Wake up periodically so the loop notices when the backend is dropped.
+        let timeout = libc::timeval {
+            tv_sec: 1,
+            tv_usec: 0,
+        };
+        set_socket_option(&socket, libc::SO_RCVTIMEO, &timeout)?;
+
+        let mut address: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        address.sll_family = libc::AF_PACKET as libc::c_ushort;
+        address.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+        let result = unsafe {
+            libc::bind(
+                socket.as_raw_fd(),
+                (&raw const address).cast(),
+                size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            )
+        };
+        if result < 0 {
+            return Err(last_os_error());
+        }
+
+        let (packet_tx, packet_rx) = queue::packet_queue(BACKEND_QUEUE_LEN, queue_stats);
+        std::thread::spawn(move || Self::packet_loop(socket, packet_tx, interfaces, selected));
+
+        Ok(Self { packet_rx })
+    }
+
+    fn packet_loop(
+        socket: OwnedFd,
+        packet_tx: PacketSender<Result<CapturedPacket>>,
+        interfaces: HashMap<i32, String>,
+        selected: Option<Vec<i32>>,
+    ) {
+        let mut buffer = vec![0u8; RECV_BUFFER_LEN];
+        // Room for the `SO_TIMESTAMP` control message, aligned for `cmsghdr`.
+        let mut control = [0u64; 8];
+        let mut has_captured = false;
+        loop {
+            if packet_tx.is_closed() {
+                tracing::info!(
+                    "AF_PACKET loop ending (has_captured: {}): channel closed",
+                    has_captured
+                );
+                break;
+            }
+
+            let mut address: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+            let mut iov = libc::iovec {
+                iov_base: buffer.as_mut_ptr().cast(),
+                iov_len: buffer.len(),
+            };
+            let mut message: libc::msghdr = unsafe { std::mem::zeroed() };
+            message.msg_name = (&raw mut address).cast();
+            message.msg_namelen = size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+            message.msg_iov = &raw mut iov;
+            message.msg_iovlen = 1;
+            message.msg_control = control.as_mut_ptr().cast();
+            message.msg_controllen = size_of_val(&control) as _;
+            let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &raw mut message, 0) };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock
+                        | io::ErrorKind::TimedOut
+                        | io::ErrorKind::Interrupted
+                ) {
+                    continue;
+                }
+                tracing::info!(
+                    "AF_PACKET loop ending (has_captured: {}): capture error: {}",
+                    has_captured,
+                    err
+                );
+                let _ = packet_tx.blocking_send(Err(CaptureError::Capture {
+                    has_captured,
+                    error: err.into(),
+                }));
+                break;
+            }
+            has_captured = true;
+
+            if selected
+                .as_ref()
+                .is_some_and(|selected| !selected.contains(&address.sll_ifindex))
+            {
+                continue;
+            }
+
+            // Cooked sockets strip the link layer, leaving the IP packet.
+            let Some(data) = link::normalize(LinkType::Raw, &buffer[..len as usize]) else {
+                continue;
+            };
+            let packet = CapturedPacket::new(
+                data,
+                kernel_timestamp(&message).unwrap_or_else(SystemTime::now),
+                interfaces.get(&address.sll_ifindex).cloned(),
+            );
+            if packet_tx.try_send(Ok(packet)).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl CaptureBackend for AfPacketBackend {
+    async fn next_packet(&mut self) -> Result<CapturedPacket> {
+        match self.packet_rx.recv().await {
+            Some(Ok(packet)) => Ok(packet),
+            Some(Err(err)) => Err(err),
+            None => Err(CaptureError::CaptureClosed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run `program` the way the kernel would on a packet of ethertype
+    /// `protocol`, returning the number of bytes accepted.
+    fn run(program: &[libc::sock_filter], protocol: u32, packet: &[u8]) -> u32 {
+        let byte = |offset: u32| packet.get(offset as usize).copied().map(u32::from);
+        let half = |offset: u32| Some((byte(offset)? << 8) | byte(offset + 1)?);
+        let (mut a, mut x, mut pc) = (0u32, 0u32, 0usize);
+        loop {
+            let insn = program[pc];
+            pc += 1;
+            let taken = match insn.code {
+                BPF_LDB_ABS => {
+                    let Some(value) = byte(insn.k) else { return 0 };
+                    a = value;
+                    continue;
+                }
+                BPF_LDH_ABS if insn.k == SKF_AD_PROTOCOL => {
+                    a = protocol;
+                    continue;
+                }
+                BPF_LDH_ABS | BPF_LDH_IND => {
+                    let offset = if insn.code == BPF_LDH_IND {
+                        x + insn.k
+                    } else {
+                        insn.k
+                    };
+                    let Some(value) = half(offset) else { return 0 };
+                    a = value;
+                    continue;
+                }
+                BPF_LDXB_MSH => {
+                    let Some(value) = byte(insn.k) else { return 0 };
+                    x = (value & 0xf) * 4;
+                    continue;
+                }
+                BPF_AND_K => {
+                    a &= insn.k;
+                    continue;
+                }
+                BPF_RET_K => return insn.k,
+                BPF_JEQ_K => a == insn.k,
+                BPF_JGT_K => a > insn.k,
+                BPF_JGE_K => a >= insn.k,
+                BPF_JSET_K => a & insn.k != 0,
+                code => panic!("unexpected opcode {code:#x}"),
+            };
+            pc += usize::from(if taken { insn.jt } else { insn.jf });
+        }
+    }
+
+    fn ipv4(protocol: u8, fragment: u16, src: u16, dst: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 28];
+        packet[0] = 0x45;
+        packet[6..8].copy_from_slice(&fragment.to_be_bytes());
+        packet[9] = protocol;
+        packet[20..22].copy_from_slice(&src.to_be_bytes());
+        packet[22..24].copy_from_slice(&dst.to_be_bytes());
+        packet
+    }
+
+    fn ipv6(next_header: u8, src: u16, dst: u16) -> Vec<u8> {
+        let mut packet = vec![0u8; 48];
+        packet[0] = 0x60;
+        packet[6] = next_header;
+        packet[40..42].copy_from_slice(&src.to_be_bytes());
+        packet[42..44].copy_from_slice(&dst.to_be_bytes());
+        packet
+    }
+
+    fn accepts(program: &[libc::sock_filter], protocol: u32, packet: &[u8]) -> bool {
+        run(program, protocol, packet) == RECV_BUFFER_LEN as u32
+    }
+
+    #[test]
+    fn filters_ports() {
+        let ports: PortRanges = "22101-22102,23301".parse().unwrap();
+        let program = kernel_filter(Some(&ports)).unwrap();
+
+        for port in [22101, 22102, 23301] {
+            assert!(accepts(&program, ETH_P_IP, &ipv4(17, 0, 50000, port)));
+            assert!(accepts(&program, ETH_P_IP, &ipv4(17, 0, port, 50000)));
+            assert!(accepts(&program, ETH_P_IPV6, &ipv6(17, 50000, port)));
+            assert!(accepts(&program, ETH_P_IPV6, &ipv6(17, port, 50000)));
+        }
+        for port in [22100, 22103, 23300, 23302] {
+            assert!(!accepts(&program, ETH_P_IP, &ipv4(17, 0, 50000, port)));
+            assert!(!accepts(&program, ETH_P_IPV6, &ipv6(17, port, 50000)));
+        }
+        assert!(!accepts(&program, ETH_P_IP, &ipv4(6, 0, 50000, 22101)));
+        assert!(!accepts(&program, ETH_P_IPV6, &ipv6(6, 50000, 22101)));
+    }
+
+    #[test]
+    fn accepts_fragments() {
+        let ports: PortRanges = "22101-22102".parse().unwrap();
+        let program = kernel_filter(Some(&ports)).unwrap();
+
+        // A later fragment has no UDP header, whatever its "ports" read.
+        assert!(accepts(&program, ETH_P_IP, &ipv4(17, 0x00b9, 1, 1)));
+        // The first fragment still carries one.
+        assert!(!accepts(&program, ETH_P_IP, &ipv4(17, 0x2000, 1, 1)));
+        assert!(accepts(&program, ETH_P_IPV6, &ipv6(44, 1, 1)));
+    }
+
+    #[test]
+    fn matches_any_udp_without_ports() {
+        let program = kernel_filter(None).unwrap();
+
+        assert!(accepts(&program, ETH_P_IP, &ipv4(17, 0, 1, 1)));
+        assert!(accepts(&program, ETH_P_IPV6, &ipv6(17, 1, 1)));
+        assert!(accepts(&program, ETH_P_IPV6, &ipv6(44, 1, 1)));
+        assert!(!accepts(&program, ETH_P_IP, &ipv4(6, 0, 1, 1)));
+    }
+
+    #[test]
+    fn drops_non_ip() {
+        let ports: PortRanges = "22101".parse().unwrap();
+        let program = kernel_filter(Some(&ports)).unwrap();
+
+        // An ARP frame that happens to look like IPv4 UDP to port 22101.
+        const ETH_P_ARP: u32 = 0x0806;
+        assert!(!accepts(&program, ETH_P_ARP, &ipv4(17, 0, 1, 22101)));
+        assert!(!accepts(&program, ETH_P_ARP, &ipv6(17, 1, 22101)));
+    }
+
+    #[test]
+    fn rejects_programs_out_of_jump_range() {
+        let ports: PortRanges = (0..100)
+            .map(|i| format!("{}-{}", i * 10, i * 10 + 1))
+            .collect::<Vec<_>>()
+            .join(",")
+            .parse()
+            .unwrap();
+        assert!(kernel_filter(Some(&ports)).is_none());
+    }
+}
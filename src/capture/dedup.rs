@@ -0,0 +1,142 @@
+use std::collections::{HashSet, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::{Duration, Instant};
+
+use crate::capture::CapturedPacket;
+use crate::capture::parse::parse_ethernet_udp;
+
+/// How long a datagram is remembered.  Copies of the same datagram seen on
+/// several interfaces arrive well within this window, while KCP retransmits
+/// carry an updated timestamp and so hash differently.
+const DEDUP_WINDOW: Duration = Duration::from_millis(100);
+
+/// Drops UDP datagrams that were already seen recently, e.g. when capturing on
+/// both a bridge and the physical interface behind it.
+pub struct Deduplicator {
+    seen: HashSet<u64>,
+    expiry: VecDeque<(Instant, u64)>,
+    duplicates: u64,
+}
+
+impl Deduplicator {
+    pub fn new() -> Self {
+        Self {
+            seen: HashSet::new(),
+            expiry: VecDeque::new(),
+            duplicates: 0,
+        }
+    }
+
+    /// Number of packets dropped as duplicates so far.
+    pub fn duplicates(&self) -> u64 {
+        self.duplicates
+    }
+
+    /// Returns `true` if `packet` should be passed on.  Packets that aren't
+    /// UDP datagrams are always passed on.
+    pub fn check(&mut self, packet: &CapturedPacket) -> bool {
+        self.check_at(packet, Instant::now())
+    }
+
+    fn check_at(&mut self, packet: &CapturedPacket, now: Instant) -> bool {
+        let (Some(src), Some(dst)) = (packet.src, packet.dst) else {
+            return true;
+        };
+        let Some(datagram) = parse_ethernet_udp(&packet.data) else {
+            return true;
+        };
+
+        // Link layer headers differ between interfaces so only the 5-tuple
+        // (the protocol is always UDP) and payload are considered.
+        let mut hasher = DefaultHasher::new();
+        src.hash(&mut hasher);
+        dst.hash(&mut hasher);
+        datagram.payload.hash(&mut hasher);
+        let key = hasher.finish();
+
+        while let Some((seen_at, expired_key)) = self.expiry.front() {
+            if now.duration_since(*seen_at) < DEDUP_WINDOW {
+                break;
+            }
+            self.seen.remove(expired_key);
+            self.expiry.pop_front();
+        }
+
+        if !self.seen.insert(key) {
+            self.duplicates += 1;
+            return false;
+        }
+        self.expiry.push_back((now, key));
+        true
+    }
+}
+
+impl Default for Deduplicator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::capture::parse::{ETHERTYPE_IPV4, IP_PROTOCOL_UDP};
+
+    /// A UDP datagram from 10.0.0.1:`src_port` to 10.0.0.2:5000, seen on
+    /// `interface`.
+    fn udp_packet(src_port: u16, payload: &[u8], interface: &str) -> CapturedPacket {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&[0x45, 0]);
+        frame.extend_from_slice(&((28 + payload.len()) as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 0, 0, 0, 64, IP_PROTOCOL_UDP, 0, 0]);
+        frame.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        frame.extend_from_slice(&src_port.to_be_bytes());
+        frame.extend_from_slice(&5000u16.to_be_bytes());
+        frame.extend_from_slice(&((8 + payload.len()) as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(payload);
+        CapturedPacket::new(frame, SystemTime::UNIX_EPOCH, Some(interface.to_string()))
+    }
+
+    #[test]
+    fn drops_duplicates_within_window() {
+        let mut deduplicator = Deduplicator::new();
+        let now = Instant::now();
+        assert!(deduplicator.check_at(&udp_packet(22101, b"payload", "eth0"), now));
+        assert!(!deduplicator.check_at(
+            &udp_packet(22101, b"payload", "br0"),
+            now + DEDUP_WINDOW / 2
+        ));
+        assert_eq!(deduplicator.duplicates(), 1);
+    }
+
+    #[test]
+    fn keeps_duplicates_outside_window() {
+        let mut deduplicator = Deduplicator::new();
+        let now = Instant::now();
+        assert!(deduplicator.check_at(&udp_packet(22101, b"payload", "eth0"), now));
+        assert!(deduplicator.check_at(&udp_packet(22101, b"payload", "br0"), now + DEDUP_WINDOW));
+        assert_eq!(deduplicator.duplicates(), 0);
+    }
+
+    #[test]
+    fn keeps_same_payload_between_other_addresses() {
+        let mut deduplicator = Deduplicator::new();
+        let now = Instant::now();
+        assert!(deduplicator.check_at(&udp_packet(22101, b"payload", "eth0"), now));
+        assert!(deduplicator.check_at(&udp_packet(22102, b"payload", "eth0"), now));
+        assert!(deduplicator.check_at(&udp_packet(22101, b"other", "eth0"), now));
+        assert_eq!(deduplicator.duplicates(), 0);
+    }
+
+    #[test]
+    fn passes_non_udp_packets() {
+        let mut deduplicator = Deduplicator::new();
+        let packet = CapturedPacket::new(vec![0; 64], SystemTime::UNIX_EPOCH, None);
+        assert!(deduplicator.check(&packet));
+        assert!(deduplicator.check(&packet));
+    }
+}
@@ -0,0 +1,573 @@
+//! Privilege-separated capture on Linux.
+//!
+//! When Irminsul runs without packet capture privileges it spawns itself
+//! through `pkexec` with `--capture-helper <socket>`.  The helper connects
+//! back over a Unix socket, captures with the requested backend and streams
+//! the packets to the unprivileged GUI.
+//!
+//! Requests from the GUI are JSON lines.  Replies are frames of a one byte
+//! kind, a little endian `u32` session and body length, and the body.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, anyhow};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::process::{Child, Command};
+use tokio::sync::mpsc::Receiver;
+use tokio_util::sync::CancellationToken;
+
+use crate::capture::queue::{self, BACKEND_QUEUE_LEN, PacketSender, QueueStats};
+use crate::capture::{
+    BackendType, CaptureBackend, CaptureConfig, CaptureError, CapturedPacket, Result,
+    create_capture,
+};
+
+const FRAME_PACKET: u8 = 0;
+const FRAME_CAPTURE_ERROR: u8 = 1;
+const FRAME_FILTER_ERROR: u8 = 2;
+const FRAME_CLOSED: u8 = 3;
+const FRAME_PERMISSION_ERROR: u8 = 4;
+const FRAME_UNSUPPORTED: u8 = 5;
+const FRAME_HEADER_LEN: usize = 9;
+
+/// Frames larger than this mean the stream is out of sync.
+const MAX_FRAME_LEN: usize = 256 * 1024;
+
+#[derive(Debug, Deserialize, Serialize)]
+enum HelperRequest {
+    Start {
+        session: u32,
+        backend: BackendType,
+        config: CaptureConfig,
+    },
+    Stop {
+        session: u32,
+    },
+}
+
+/// Whether captures should go through the helper: we lack the privileges to
+/// capture ourselves and `pkexec` is available to start the helper.
+pub fn needed() -> bool {
+    !crate::admin::has_capture_privileges() && available()
+}
+
+/// Whether the helper can be started, should we lack capture privileges.
+pub fn available() -> bool {
+    pkexec_path().is_some()
+}
+
+fn pkexec_path() -> Option<PathBuf> {
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join("pkexec"))
+        .find(|path| path.is_file())
+}
+
+fn frame(kind: u8, session: u32, body: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + body.len());
+    frame.push(kind);
+    frame.extend_from_slice(&session.to_le_bytes());
+    frame.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    frame.extend_from_slice(body);
+    frame
+}
+
+fn packet_frame(session: u32, packet: &CapturedPacket) -> Vec<u8> {
+    let timestamp = packet
+        .timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let interface = packet.interface.as_deref().unwrap_or("").as_bytes();
+    let interface = &interface[..interface.len().min(u8::MAX.into())];
+
+    let mut body = Vec::with_capacity(9 + interface.len() + packet.data.len());
+    body.extend_from_slice(&timestamp.to_le_bytes());
+    body.push(interface.len() as u8);
+    body.extend_from_slice(interface);
+    body.extend_from_slice(&packet.data);
+    frame(FRAME_PACKET, session, &body)
+}
+
+fn error_frame(session: u32, error: &CaptureError) -> Vec<u8> {
+    match error {
+        CaptureError::Filter(e) => frame(FRAME_FILTER_ERROR, session, e.to_string().as_bytes()),
+        CaptureError::Capture {
+            has_captured,
+            error,
+        } => {
+            let mut body = vec![u8::from(*has_captured)];
+            body.extend_from_slice(error.to_string().as_bytes());
+            frame(FRAME_CAPTURE_ERROR, session, &body)
+        }
+        CaptureError::Permission(e) => {
+            frame(FRAME_PERMISSION_ERROR, session, e.to_string().as_bytes())
+        }
+        CaptureError::Unsupported(e) => frame(FRAME_UNSUPPORTED, session, e.to_string().as_bytes()),
+        CaptureError::CaptureClosed | CaptureError::ChannelClosed => {
+            frame(FRAME_CLOSED, session, &[])
+        }
+    }
+}
+
+/// The kind, session and body length of a frame.
+fn parse_frame_header(header: &[u8; FRAME_HEADER_LEN]) -> anyhow::Result<(u8, u32, usize)> {
+    let kind = header[0];
+    let session = u32::from_le_bytes(header[1..5].try_into()?);
+    let len = u32::from_le_bytes(header[5..9].try_into()?) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(anyhow!("capture helper frame of {len} bytes is too large"));
+    }
+    Ok((kind, session, len))
+}
+
+/// The packet or error carried by a frame's body.  Fails if the frame is
+/// malformed, in which case the stream can't be trusted any further.
+fn parse_frame(kind: u8, body: &[u8]) -> anyhow::Result<Result<CapturedPacket>> {
+    let text = |body: &[u8]| anyhow!("{}", String::from_utf8_lossy(body));
+    Ok(match kind {
+        FRAME_PACKET => {
+            let timestamp = u64::from_le_bytes(
+                body.get(..8)
+                    .context("truncated packet frame")?
+                    .try_into()?,
+            );
+            let interface_len = usize::from(*body.get(8).context("truncated packet frame")?);
+            let interface = body
+                .get(9..9 + interface_len)
+                .context("truncated packet frame")?;
+            let interface =
+                (!interface.is_empty()).then(|| String::from_utf8_lossy(interface).into_owned());
+            Ok(CapturedPacket::new(
+                body[9 + interface_len..].to_vec(),
+                SystemTime::UNIX_EPOCH + Duration::from_micros(timestamp),
+                interface,
+            ))
+        }
+        FRAME_CAPTURE_ERROR => Err(CaptureError::Capture {
+            has_captured: body.first() == Some(&1),
+            error: text(body.get(1..).unwrap_or(&[])),
+        }),
+        FRAME_FILTER_ERROR => Err(CaptureError::Filter(text(body))),
+        FRAME_PERMISSION_ERROR => Err(CaptureError::Permission(text(body))),
+        FRAME_UNSUPPORTED => Err(CaptureError::Unsupported(text(body))),
+        FRAME_CLOSED => Err(CaptureError::CaptureClosed),
+        _ => return Err(anyhow!("unknown capture helper frame {kind}")),
+    })
+}
+
+/// Entry point of the helper process.  Runs until the GUI closes the socket.
+pub fn run(socket_path: &Path) -> anyhow::Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let stream = UnixStream::connect(socket_path)
+            .await
+            .with_context(|| format!("can't connect to {socket_path:?}"))?;
+        let (reader, writer) = stream.into_split();
+        let writer = Arc::new(tokio::sync::Mutex::new(writer));
+        let mut requests = BufReader::new(reader).lines();
+        let mut current: Option<(u32, CancellationToken)> = None;
+
+        tracing::info!("Capture helper connected to {socket_path:?}");
+        while let Some(line) = requests.next_line().await? {
+            match serde_json::from_str(&line)? {
+                HelperRequest::Start {
+                    session,
+                    backend,
+                    config,
+                } => {
+                    if let Some((_, cancel_token)) = current.take() {
+                        cancel_token.cancel();
+                    }
+                    // Never let the GUI make the helper open sockets or read
+                    // its stdin as root.
+                    if !matches!(backend, BackendType::Pcap | BackendType::AfPacket) {
+                        let error = CaptureError::Unsupported(anyhow!(
+                            "{backend:?} capture is not supported by the capture helper"
+                        ));
+                        writer
+                            .lock()
+                            .await
+                            .write_all(&error_frame(session, &error))
+                            .await?;
+                        continue;
+                    }
+
+                    let cancel_token = CancellationToken::new();
+                    tokio::spawn(forward(
+                        session,
+                        backend,
+                        config,
+                        writer.clone(),
+                        cancel_token.clone(),
+                    ));
+                    current = Some((session, cancel_token));
+                }
+                HelperRequest::Stop { session } => {
+                    if current
+                        .as_ref()
+                        .is_some_and(|(current, _)| *current == session)
+                    {
+                        let (_, cancel_token) = current.take().unwrap();
+                        cancel_token.cancel();
+                    }
+                }
+            }
+        }
+        tracing::info!("Capture helper disconnected");
+
+        Ok(())
+    })
+}
+
+/// Capture in the helper and write the packets to the GUI.
+async fn forward(
+    session: u32,
+    backend: BackendType,
+    config: CaptureConfig,
+    writer: Arc<tokio::sync::Mutex<OwnedWriteHalf>>,
+    cancel_token: CancellationToken,
+) {
+    let mut capture = match create_capture(backend, &config, Arc::new(QueueStats::default())) {
+        Ok(capture) => capture,
+        Err(e) => {
+            let _ = writer
+                .lock()
+                .await
+                .write_all(&error_frame(session, &e))
+                .await;
+            return;
+        }
+    };
+
+    loop {
+        let packet = tokio::select!(
+            packet = capture.next_packet() => packet,
+            _ = cancel_token.cancelled() => break,
+        );
+        let frame = match &packet {
+            Ok(packet) => packet_frame(session, packet),
+            Err(e) => error_frame(session, e),
+        };
+        if writer.lock().await.write_all(&frame).await.is_err() {
+            break;
+        }
+        if matches!(
+            packet,
+            Err(CaptureError::CaptureClosed | CaptureError::ChannelClosed)
+        ) {
+            break;
+        }
+    }
+}
+
+/// The GUI's connection to a running helper.
+struct HelperClient {
+    writer: tokio::sync::Mutex<OwnedWriteHalf>,
+    /// Where packets of the active session go.
+    current: Mutex<Option<(u32, PacketSender<Result<CapturedPacket>>)>>,
+    closed: AtomicBool,
+    /// Keeps the helper alive; it is killed along with the client.
+    _child: Child,
+}
+
+static CLIENT: tokio::sync::Mutex<Option<Arc<HelperClient>>> = tokio::sync::Mutex::const_new(None);
+static NEXT_SESSION: AtomicU32 = AtomicU32::new(0);
+
+impl HelperClient {
+    /// The running helper, starting it if needed.
+    async fn get() -> Result<Arc<HelperClient>> {
+        let mut client = CLIENT.lock().await;
+        if let Some(client) = client.as_ref()
+            && !client.closed.load(Ordering::Relaxed)
+        {
+            return Ok(client.clone());
+        }
+
+        let new_client = Self::spawn().await?;
+        *client = Some(new_client.clone());
+        Ok(new_client)
+    }
+
+    async fn spawn() -> Result<Arc<HelperClient>> {
+        let capture_error = |error: anyhow::Error| CaptureError::Capture {
+            has_captured: false,
+            error,
+        };
+
+        // The socket lives in a directory only we can enter, so no other user
+        // can connect to it before the helper does.  Both are removed when
+        // `socket_dir` is dropped.
+        let dir = std::env::var_os("XDG_RUNTIME_DIR")
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir);
+        let socket_dir = tempfile::Builder::new()
+            .prefix("irminsul-capture-")
+            .tempdir_in(&dir)
+            .with_context(|| format!("can't create a socket directory in {dir:?}"))
+            .map_err(capture_error)?;
+        let socket_path = socket_dir.path().join("capture.sock");
+        let listener = UnixListener::bind(&socket_path)
+            .with_context(|| format!("can't listen on {socket_path:?}"))
+            .map_err(capture_error)?;
+
+        let exe = std::env::current_exe().map_err(|e| capture_error(e.into()))?;
+        tracing::info!("Starting capture helper through pkexec");
+        let mut child = Command::new("pkexec")
+            .arg(exe)
+            .arg("--capture-helper")
+            .arg(&socket_path)
+            .kill_on_drop(true)
+            .spawn()
+            .context("can't run pkexec")
+            .map_err(capture_error)?;
+
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            status = child.wait() => {
+                // pkexec exits with 126 when the dialog is dismissed and 127
+                // when authorization fails.
+                return Err(CaptureError::Permission(match status {
+                    Ok(status) => anyhow!("Capture helper exited ({status})"),
+                    Err(e) => e.into(),
+                }));
+            }
+        };
+        drop(socket_dir);
+        let (stream, _) = accepted.map_err(|e| capture_error(e.into()))?;
+
+        // Only the helper started through pkexec runs as root.
+        let peer = stream.peer_cred().map_err(|e| capture_error(e.into()))?;
+        if peer.uid() != 0 {
+            return Err(capture_error(anyhow!(
+                "Capture helper connection from unexpected user {}",
+                peer.uid()
+            )));
+        }
+        tracing::info!("Capture helper connected");
+
+        let (reader, writer) = stream.into_split();
+        let client = Arc::new(HelperClient {
+            writer: tokio::sync::Mutex::new(writer),
+            current: Mutex::new(None),
+            closed: AtomicBool::new(false),
+            _child: child,
+        });
+        tokio::spawn(Self::read_loop(client.clone(), reader));
+
+        Ok(client)
+    }
+
+    async fn send(&self, request: &HelperRequest) -> Result<()> {
+        let mut line = serde_json::to_string(request).map_err(|e| CaptureError::Capture {
+            has_captured: false,
+            error: e.into(),
+        })?;
+        line.push('\n');
+        self.writer
+            .lock()
+            .await
+            .write_all(line.as_bytes())
+            .await
+            .map_err(|_| CaptureError::ChannelClosed)
+    }
+
+    async fn read_loop(client: Arc<HelperClient>, mut reader: OwnedReadHalf) {
+        if let Err(e) = client.read_frames(&mut reader).await {
+            tracing::warn!("Capture helper connection lost: {e:#}");
+        }
+        client.closed.store(true, Ordering::Relaxed);
+        // Closing the active session's queue ends its capture.
+        client.current.lock().unwrap().take();
+    }
+
+    async fn read_frames(&self, reader: &mut OwnedReadHalf) -> anyhow::Result<()> {
+        loop {
+            let mut header = [0; FRAME_HEADER_LEN];
+            match reader.read_exact(&mut header).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+            let (kind, session, len) = parse_frame_header(&header)?;
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).await?;
+            let item = parse_frame(kind, &body)?;
+
+            // Frames of stopped sessions may still be in flight.
+            let current = self.current.lock().unwrap();
+            if let Some((current_session, packet_tx)) = current.as_ref()
+                && *current_session == session
+            {
+                let _ = packet_tx.try_send(item);
+            }
+        }
+    }
+}
+
+/// Captures through the helper process.
+pub struct HelperBackend {
+    session: u32,
+    packet_rx: Receiver<Result<CapturedPacket>>,
+}
+
+impl HelperBackend {
+    pub fn new(
+        backend: BackendType,
+        config: &CaptureConfig,
+        queue_stats: Arc<QueueStats>,
+    ) -> Result<Self> {
+        let session = NEXT_SESSION.fetch_add(1, Ordering::Relaxed);
+        let (packet_tx, packet_rx) = queue::packet_queue(BACKEND_QUEUE_LEN, queue_stats);
+
+        // Starting the helper waits for the user to authorize it, so do it in
+        // the background.
+        let config = config.clone();
+        tokio::spawn(async move {
+            let result = async {
+                let client = HelperClient::get().await?;
+                // Capture may have been stopped while waiting for the helper.
+                if packet_tx.is_closed() {
+                    return Ok(());
+                }
+                *client.current.lock().unwrap() = Some((session, packet_tx.clone()));
+                client
+                    .send(&HelperRequest::Start {
+                        session,
+                        backend,
+                        config,
+                    })
+                    .await
+            }
+            .await;
+            if let Err(e) = result {
+                let _ = packet_tx.send(Err(e)).await;
+            }
+        });
+
+        Ok(Self { session, packet_rx })
+    }
+}
+
+impl Drop for HelperBackend {
+    fn drop(&mut self) {
+        let session = self.session;
+        tokio::spawn(async move {
+            let Some(client) = CLIENT.lock().await.clone() else {
+                return;
+            };
+            {
+                let mut current = client.current.lock().unwrap();
+                if current
+                    .as_ref()
+                    .is_some_and(|(current, _)| *current == session)
+                {
+                    current.take();
+                }
+            }
+            let _ = client.send(&HelperRequest::Stop { session }).await;
+        });
+    }
+}
+
+#[async_trait]
+impl CaptureBackend for HelperBackend {
+    async fn next_packet(&mut self) -> Result<CapturedPacket> {
+        match self.packet_rx.recv().await {
+            Some(Ok(packet)) => Ok(packet),
+            Some(Err(err)) => Err(err),
+            None => Err(CaptureError::CaptureClosed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Split an encoded frame the way the GUI reads it.
+    fn decode(frame: &[u8]) -> (u32, Result<CapturedPacket>) {
+        let (kind, session, len) =
+            parse_frame_header(frame[..FRAME_HEADER_LEN].try_into().unwrap()).unwrap();
+        let body = &frame[FRAME_HEADER_LEN..];
+        assert_eq!(body.len(), len);
+        (session, parse_frame(kind, body).unwrap())
+    }
+
+    fn decode_error(frame: &[u8]) -> CaptureError {
+        match decode(frame) {
+            (_, Err(e)) => e,
+            (_, Ok(_)) => panic!("expected an error frame"),
+        }
+    }
+
+    #[test]
+    fn round_trips_packets() {
+        let timestamp = SystemTime::UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let packet = CapturedPacket::new(vec![1, 2, 3], timestamp, Some("eth0".to_string()));
+        let (session, decoded) = decode(&packet_frame(7, &packet));
+        let decoded = decoded.unwrap();
+        assert_eq!(session, 7);
+        assert_eq!(decoded.data, [1, 2, 3]);
+        assert_eq!(decoded.timestamp, timestamp);
+        assert_eq!(decoded.interface.as_deref(), Some("eth0"));
+
+        let packet = CapturedPacket::new(Vec::new(), timestamp, None);
+        let decoded = decode(&packet_frame(8, &packet)).1.unwrap();
+        assert!(decoded.data.is_empty());
+        assert_eq!(decoded.interface, None);
+    }
+
+    #[test]
+    fn round_trips_errors() {
+        let error = CaptureError::Capture {
+            has_captured: true,
+            error: anyhow!("device gone"),
+        };
+        assert!(matches!(
+            decode_error(&error_frame(1, &error)),
+            CaptureError::Capture { has_captured: true, error } if error.to_string() == "device gone"
+        ));
+
+        let error = CaptureError::Filter(anyhow!("bad filter"));
+        assert!(matches!(
+            decode_error(&error_frame(1, &error)),
+            CaptureError::Filter(e) if e.to_string() == "bad filter"
+        ));
+
+        let error = CaptureError::Permission(anyhow!("dismissed"));
+        assert!(matches!(
+            decode_error(&error_frame(1, &error)),
+            CaptureError::Permission(e) if e.to_string() == "dismissed"
+        ));
+
+        let error = CaptureError::Unsupported(anyhow!("no device"));
+        assert!(matches!(
+            decode_error(&error_frame(1, &error)),
+            CaptureError::Unsupported(e) if e.to_string() == "no device"
+        ));
+
+        assert!(matches!(
+            decode_error(&error_frame(1, &CaptureError::ChannelClosed)),
+            CaptureError::CaptureClosed
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        let mut header = frame(FRAME_PACKET, 0, &[]);
+        header[5..9].copy_from_slice(&(MAX_FRAME_LEN as u32 + 1).to_le_bytes());
+        assert!(parse_frame_header(header[..].try_into().unwrap()).is_err());
+
+        assert!(parse_frame(FRAME_PACKET, &[0; 8]).is_err());
+        assert!(parse_frame(FRAME_PACKET, &[0, 0, 0, 0, 0, 0, 0, 0, 4, b'e']).is_err());
+        assert!(parse_frame(42, &[]).is_err());
+    }
+}
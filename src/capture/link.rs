@@ -0,0 +1,229 @@
+use crate::capture::parse::{ETHERNET_HEADER_LEN, ETHERTYPE_IPV4, ETHERTYPE_IPV6, be_u16};
+
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+const VLAN_TAG_LEN: usize = 4;
+
+const NULL_HEADER_LEN: usize = 4;
+const LINUX_SLL_HEADER_LEN: usize = 16;
+const LINUX_SLL2_HEADER_LEN: usize = 20;
+
+/// Link layer formats captured frames can arrive in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LinkType {
+    Ethernet,
+    /// BSD loopback with the address family in host byte order.
+    Null,
+    /// OpenBSD loopback with the address family in network byte order.
+    Loop,
+    /// Bare IPv4 or IPv6 packets, e.g. from tun or WireGuard interfaces.
+    Raw,
+    /// Linux "cooked" capture, used by the `any` device.
+    LinuxSll,
+    LinuxSll2,
+}
+
+impl LinkType {
+    /// Map a libpcap `DLT_*` value to a supported link type.
+    pub fn from_dlt(dlt: i32) -> Option<Self> {
+        match dlt {
+            0 => Some(Self::Null),
+            1 => Some(Self::Ethernet),
+            // DLT_RAW is 12 on most platforms and 14 on OpenBSD, DLT_IPV4 and
+            // DLT_IPV6 are 228 and 229.
+            12 | 14 | 101 | 228 | 229 => Some(Self::Raw),
+            108 => Some(Self::Loop),
+            113 => Some(Self::LinuxSll),
+            276 => Some(Self::LinuxSll2),
+            _ => None,
+        }
+    }
+}
+
+/// Ethertype matching the version of a bare IP packet.
+fn ip_ethertype(packet: &[u8]) -> Option<u16> {
+    match packet.first()? >> 4 {
+        4 => Some(ETHERTYPE_IPV4),
+        6 => Some(ETHERTYPE_IPV6),
+        _ => None,
+    }
+}
+
+/// Convert a frame of the given link type into the Ethernet frame the rest of
+/// the pipeline and the sniffer expect, stripping any VLAN tags.  Returns
+/// `None` for frames that don't carry IP.
+pub fn normalize(link_type: LinkType, frame: &[u8]) -> Option<Vec<u8>> {
+    let (mut ethertype, mut payload) = match link_type {
+        LinkType::Ethernet => {
+            let ethertype = be_u16(frame, 12)?;
+            if ethertype != ETHERTYPE_VLAN && ethertype != ETHERTYPE_QINQ {
+                return Some(frame.to_vec());
+            }
+            (ethertype, frame.get(ETHERNET_HEADER_LEN..)?)
+        }
+        // The address family values for IPv6 differ between platforms, so
+        // rely on the IP version instead.
+        LinkType::Null | LinkType::Loop | LinkType::Raw => {
+            let header_len = if link_type == LinkType::Raw {
+                0
+            } else {
+                NULL_HEADER_LEN
+            };
+            let packet = frame.get(header_len..)?;
+            (ip_ethertype(packet)?, packet)
+        }
+        LinkType::LinuxSll => (be_u16(frame, 14)?, frame.get(LINUX_SLL_HEADER_LEN..)?),
+        LinkType::LinuxSll2 => (be_u16(frame, 0)?, frame.get(LINUX_SLL2_HEADER_LEN..)?),
+    };
+
+    while ethertype == ETHERTYPE_VLAN || ethertype == ETHERTYPE_QINQ {
+        ethertype = be_u16(payload, 2)?;
+        payload = payload.get(VLAN_TAG_LEN..)?;
+    }
+    if ethertype != ETHERTYPE_IPV4 && ethertype != ETHERTYPE_IPV6 {
+        return None;
+    }
+
+    // Reuse the original MAC addresses when there are any.
+    let mut normalized = Vec::with_capacity(ETHERNET_HEADER_LEN + payload.len());
+    match link_type {
+        LinkType::Ethernet => normalized.extend_from_slice(&frame[..12]),
+        _ => normalized.extend_from_slice(&[0; 12]),
+    }
+    normalized.extend_from_slice(&ethertype.to_be_bytes());
+    normalized.extend_from_slice(payload);
+
+    Some(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MACS: [u8; 12] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12];
+
+    fn ipv4_packet() -> Vec<u8> {
+        let mut packet = vec![0; 20];
+        packet[0] = 0x45;
+        packet
+    }
+
+    fn ipv6_packet() -> Vec<u8> {
+        let mut packet = vec![0; 40];
+        packet[0] = 0x60;
+        packet
+    }
+
+    fn ethernet(macs: &[u8], ethertype: u16, packet: &[u8]) -> Vec<u8> {
+        let mut frame = macs.to_vec();
+        frame.extend_from_slice(&ethertype.to_be_bytes());
+        frame.extend_from_slice(packet);
+        frame
+    }
+
+    #[test]
+    fn maps_dlt_values() {
+        assert_eq!(LinkType::from_dlt(1), Some(LinkType::Ethernet));
+        assert_eq!(LinkType::from_dlt(0), Some(LinkType::Null));
+        assert_eq!(LinkType::from_dlt(108), Some(LinkType::Loop));
+        assert_eq!(LinkType::from_dlt(12), Some(LinkType::Raw));
+        assert_eq!(LinkType::from_dlt(229), Some(LinkType::Raw));
+        assert_eq!(LinkType::from_dlt(113), Some(LinkType::LinuxSll));
+        assert_eq!(LinkType::from_dlt(276), Some(LinkType::LinuxSll2));
+        assert_eq!(LinkType::from_dlt(105), None);
+    }
+
+    #[test]
+    fn keeps_untagged_ethernet() {
+        let frame = ethernet(&MACS, ETHERTYPE_IPV4, &ipv4_packet());
+        assert_eq!(normalize(LinkType::Ethernet, &frame), Some(frame));
+    }
+
+    #[test]
+    fn strips_vlan_tags() {
+        let mut tagged = vec![0x00, 0x01];
+        tagged.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+        tagged.extend_from_slice(&ipv6_packet());
+        let frame = ethernet(&MACS, ETHERTYPE_VLAN, &tagged);
+        assert_eq!(
+            normalize(LinkType::Ethernet, &frame),
+            Some(ethernet(&MACS, ETHERTYPE_IPV6, &ipv6_packet()))
+        );
+
+        let mut double_tagged = vec![0x00, 0x02];
+        double_tagged.extend_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+        double_tagged.extend_from_slice(&tagged);
+        let frame = ethernet(&MACS, ETHERTYPE_QINQ, &double_tagged);
+        assert_eq!(
+            normalize(LinkType::Ethernet, &frame),
+            Some(ethernet(&MACS, ETHERTYPE_IPV6, &ipv6_packet()))
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_vlan_tags() {
+        let frame = ethernet(&MACS, ETHERTYPE_VLAN, &[0x00, 0x01, 0x08]);
+        assert_eq!(normalize(LinkType::Ethernet, &frame), None);
+    }
+
+    #[test]
+    fn normalizes_loopback() {
+        let mut frame = vec![2, 0, 0, 0];
+        frame.extend_from_slice(&ipv4_packet());
+        assert_eq!(
+            normalize(LinkType::Null, &frame),
+            Some(ethernet(&[0; 12], ETHERTYPE_IPV4, &ipv4_packet()))
+        );
+
+        let mut frame = vec![0, 0, 0, 24];
+        frame.extend_from_slice(&ipv6_packet());
+        assert_eq!(
+            normalize(LinkType::Loop, &frame),
+            Some(ethernet(&[0; 12], ETHERTYPE_IPV6, &ipv6_packet()))
+        );
+
+        assert_eq!(normalize(LinkType::Null, &[2, 0]), None);
+    }
+
+    #[test]
+    fn normalizes_raw_ip() {
+        assert_eq!(
+            normalize(LinkType::Raw, &ipv4_packet()),
+            Some(ethernet(&[0; 12], ETHERTYPE_IPV4, &ipv4_packet()))
+        );
+        assert_eq!(
+            normalize(LinkType::Raw, &ipv6_packet()),
+            Some(ethernet(&[0; 12], ETHERTYPE_IPV6, &ipv6_packet()))
+        );
+        assert_eq!(normalize(LinkType::Raw, &[0x50, 0, 0, 0]), None);
+        assert_eq!(normalize(LinkType::Raw, &[]), None);
+    }
+
+    #[test]
+    fn normalizes_linux_sll() {
+        let mut frame = vec![0; LINUX_SLL_HEADER_LEN];
+        frame[14..16].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&ipv4_packet());
+        assert_eq!(
+            normalize(LinkType::LinuxSll, &frame),
+            Some(ethernet(&[0; 12], ETHERTYPE_IPV4, &ipv4_packet()))
+        );
+
+        // ARP carries no IP.
+        frame[14..16].copy_from_slice(&0x0806u16.to_be_bytes());
+        assert_eq!(normalize(LinkType::LinuxSll, &frame), None);
+        assert_eq!(normalize(LinkType::LinuxSll, &frame[..10]), None);
+    }
+
+    #[test]
+    fn normalizes_linux_sll2() {
+        let mut frame = vec![0; LINUX_SLL2_HEADER_LEN];
+        frame[0..2].copy_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+        frame.extend_from_slice(&ipv6_packet());
+        assert_eq!(
+            normalize(LinkType::LinuxSll2, &frame),
+            Some(ethernet(&[0; 12], ETHERTYPE_IPV6, &ipv6_packet()))
+        );
+        assert_eq!(normalize(LinkType::LinuxSll2, &frame[..12]), None);
+    }
+}
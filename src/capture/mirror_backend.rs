@@ -0,0 +1,152 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc::Receiver;
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::capture::link::{self, LinkType};
+use crate::capture::pcap_stream::PcapStreamReader;
+use crate::capture::queue::{self, BACKEND_QUEUE_LEN, PacketSender, QueueStats};
+use crate::capture::{
+    CaptureBackend, CaptureConfig, CaptureError, CapturedPacket, MirrorAddress, Result,
+};
+
+/// Receives packets forwarded from another machine.
+///
+/// Over UDP every datagram holds one Ethernet frame.  Over TCP every
+/// connection carries a pcap stream, so `tcpdump -w - | nc <host> <port>` on
+/// the remote machine works as a forwarder.
+pub struct MirrorBackend {
+    packet_rx: Receiver<Result<CapturedPacket>>,
+    task: JoinHandle<()>,
+}
+
+impl MirrorBackend {
+    pub fn new(config: &CaptureConfig, queue_stats: Arc<QueueStats>) -> Result<Self> {
+        let address = config.mirror.clone().unwrap_or_default();
+        let (packet_tx, packet_rx) = queue::packet_queue(BACKEND_QUEUE_LEN, queue_stats);
+
+        let bind_error = |e: std::io::Error| CaptureError::Capture {
+            has_captured: false,
+            error: anyhow::Error::from(e).context(format!("can't listen on {address}")),
+        };
+
+        // Bind synchronously so errors are reported when the capture starts.
+        let task = match &address {
+            MirrorAddress::Udp(addr) => {
+                let socket = std::net::UdpSocket::bind(*addr).map_err(bind_error)?;
+                socket.set_nonblocking(true).map_err(bind_error)?;
+                let socket = UdpSocket::from_std(socket).map_err(bind_error)?;
+                tokio::spawn(Self::udp_loop(socket, packet_tx))
+            }
+            MirrorAddress::Tcp(addr) => {
+                let listener = std::net::TcpListener::bind(*addr).map_err(bind_error)?;
+                listener.set_nonblocking(true).map_err(bind_error)?;
+                let listener = TcpListener::from_std(listener).map_err(bind_error)?;
+                tokio::spawn(Self::tcp_loop(listener, packet_tx))
+            }
+        };
+        tracing::info!("Listening for mirrored packets on {address}");
+
+        Ok(Self { packet_rx, task })
+    }
+
+    async fn udp_loop(socket: UdpSocket, packet_tx: PacketSender<Result<CapturedPacket>>) {
+        let mut buffer = vec![0u8; 65536];
+        let mut has_captured = false;
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buffer).await {
+                Ok(received) => received,
+                Err(e) => {
+                    let _ = packet_tx
+                        .send(Err(CaptureError::Capture {
+                            has_captured,
+                            error: e.into(),
+                        }))
+                        .await;
+                    break;
+                }
+            };
+            has_captured = true;
+
+            let Some(data) = link::normalize(LinkType::Ethernet, &buffer[..len]) else {
+                continue;
+            };
+            let packet = CapturedPacket::new(data, SystemTime::now(), Some(peer.to_string()));
+            if packet_tx.try_send(Ok(packet)).is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn tcp_loop(listener: TcpListener, packet_tx: PacketSender<Result<CapturedPacket>>) {
+        // Dropping the set when this task is aborted ends every connection.
+        let mut connections = JoinSet::new();
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    tracing::info!("Mirror connection from {peer}");
+                    connections.spawn(Self::tcp_connection(stream, peer, packet_tx.clone()));
+                }
+                Err(e) => tracing::warn!("Error accepting mirror connection: {e}"),
+            }
+            while connections.try_join_next().is_some() {}
+        }
+    }
+
+    async fn tcp_connection(
+        stream: TcpStream,
+        peer: SocketAddr,
+        packet_tx: PacketSender<Result<CapturedPacket>>,
+    ) {
+        let mut reader =
+            match PcapStreamReader::new(BufReader::new(stream), Some(peer.to_string())).await {
+                Ok(reader) => reader,
+                Err(e) => {
+                    tracing::warn!("Mirror connection from {peer} failed: {e:#}");
+                    return;
+                }
+            };
+
+        loop {
+            match reader.next_packet().await {
+                // TCP slows the sender down instead of losing data.
+                Ok(Some(packet)) => {
+                    if packet_tx.send(Ok(packet)).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => {
+                    tracing::info!("Mirror connection from {peer} closed");
+                    break;
+                }
+                Err(e) => {
+                    tracing::warn!("Mirror connection from {peer} failed: {e:#}");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+impl Drop for MirrorBackend {
+    fn drop(&mut self) {
+        // Release the listening socket so the capture can be restarted.
+        self.task.abort();
+    }
+}
+
+#[async_trait]
+impl CaptureBackend for MirrorBackend {
+    async fn next_packet(&mut self) -> Result<CapturedPacket> {
+        match self.packet_rx.recv().await {
+            Some(Ok(packet)) => Ok(packet),
+            Some(Err(err)) => Err(err),
+            None => Err(CaptureError::CaptureClosed),
+        }
+    }
+}
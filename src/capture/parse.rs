@@ -0,0 +1,293 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use crate::capture::PortRanges;
+
+pub const ETHERNET_HEADER_LEN: usize = 14;
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+pub const IPV6_HEADER_LEN: usize = 40;
+pub const UDP_HEADER_LEN: usize = 8;
+pub const IP_PROTOCOL_UDP: u8 = 17;
+pub const IP_PROTOCOL_IPV6_FRAGMENT: u8 = 44;
+
+/// Mask of the "more fragments" flag and fragment offset in the IPv4 header's
+/// flags/fragment offset field.
+pub const IPV4_FRAGMENT_MASK: u16 = 0x3fff;
+
+/// The KCP connect request sent by the client is a 20 byte datagram starting
+/// with this magic and ending with `HANDSHAKE_REQUEST_MAGIC_END`.
+const HANDSHAKE_REQUEST_MAGIC_START: u32 = 0xff;
+const HANDSHAKE_REQUEST_MAGIC_END: u32 = 0xffff_ffff;
+const HANDSHAKE_LEN: usize = 20;
+
+/// A UDP datagram borrowed from a captured Ethernet frame.
+#[derive(Debug)]
+pub struct UdpDatagram<'a> {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub payload: &'a [u8],
+}
+
+impl UdpDatagram<'_> {
+    /// The port on the game server's side of the datagram.
+    pub fn server_port(&self, ports: &PortRanges) -> u16 {
+        if ports.contains(self.src.port()) {
+            self.src.port()
+        } else {
+            self.dst.port()
+        }
+    }
+
+    /// Whether this is the client's half of a KCP connection handshake.
+    pub fn is_handshake_request(&self) -> bool {
+        let payload = self.payload;
+        payload.len() == HANDSHAKE_LEN
+            && be_u32(payload, 0) == Some(HANDSHAKE_REQUEST_MAGIC_START)
+            && be_u32(payload, HANDSHAKE_LEN - 4) == Some(HANDSHAKE_REQUEST_MAGIC_END)
+    }
+}
+
+pub fn be_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+pub fn be_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Find the addresses and the offset of the UDP header in an Ethernet frame.
+fn locate_udp(frame: &[u8]) -> Option<(IpAddr, IpAddr, usize)> {
+    let ethertype = be_u16(frame, 12)?;
+    let ip = frame.get(ETHERNET_HEADER_LEN..)?;
+
+    match ethertype {
+        ETHERTYPE_IPV4 => {
+            let header_len = usize::from(*ip.first()? & 0xf) * 4;
+            // Fragments need to be reassembled before the UDP header can be
+            // trusted.
+            if ip.get(9)? != &IP_PROTOCOL_UDP || be_u16(ip, 6)? & IPV4_FRAGMENT_MASK != 0 {
+                return None;
+            }
+            let src: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+            Some((
+                Ipv4Addr::from(src).into(),
+                Ipv4Addr::from(dst).into(),
+                ETHERNET_HEADER_LEN + header_len,
+            ))
+        }
+        ETHERTYPE_IPV6 => {
+            if ip.get(6)? != &IP_PROTOCOL_UDP {
+                return None;
+            }
+            let src: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+            Some((
+                Ipv6Addr::from(src).into(),
+                Ipv6Addr::from(dst).into(),
+                ETHERNET_HEADER_LEN + IPV6_HEADER_LEN,
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Parse the UDP datagram out of an Ethernet frame, the format the capture
+/// backends hand to the sniffer.
+pub fn parse_ethernet_udp(frame: &[u8]) -> Option<UdpDatagram<'_>> {
+    let (src_ip, dst_ip, offset) = locate_udp(frame)?;
+    let udp = frame.get(offset..)?;
+
+    let src_port = be_u16(udp, 0)?;
+    let dst_port = be_u16(udp, 2)?;
+    let len = usize::from(be_u16(udp, 4)?);
+    let payload = udp.get(UDP_HEADER_LEN..len.max(UDP_HEADER_LEN).min(udp.len()))?;
+
+    Some(UdpDatagram {
+        src: SocketAddr::new(src_ip, src_port),
+        dst: SocketAddr::new(dst_ip, dst_port),
+        payload,
+    })
+}
+
+/// Update a ones' complement checksum for a 16-bit word of the checksummed
+/// data changing from `old` to `new` (RFC 1624).
+fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = u32::from(!checksum) + u32::from(!old) + u32::from(new);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    match !(sum as u16) {
+        // Zero means "no checksum" for UDP, so it's sent as all ones.
+        0 => 0xffff,
+        checksum => checksum,
+    }
+}
+
+/// Rewrite the UDP source and destination ports of an Ethernet frame in
+/// place, updating the UDP checksum to match.  IPv4 datagrams sent without a
+/// checksum are left without one.
+pub fn rewrite_udp_ports(frame: &mut [u8], map: impl Fn(u16) -> u16) {
+    let Some((_, _, offset)) = locate_udp(frame) else {
+        return;
+    };
+    let Some(udp) = frame.get_mut(offset..offset + UDP_HEADER_LEN) else {
+        return;
+    };
+
+    let mut checksum = u16::from_be_bytes([udp[6], udp[7]]);
+    for port_offset in [0, 2] {
+        let port = u16::from_be_bytes([udp[port_offset], udp[port_offset + 1]]);
+        let mapped = map(port);
+        if mapped != port {
+            udp[port_offset..port_offset + 2].copy_from_slice(&mapped.to_be_bytes());
+            // IPv6 requires a checksum, so only IPv4 may have none.
+            if checksum != 0 {
+                checksum = update_checksum(checksum, port, mapped);
+            }
+        }
+    }
+    udp[6..8].copy_from_slice(&checksum.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sum_words(data: &[u8]) -> u32 {
+        data.chunks(2)
+            .map(|word| u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])))
+            .sum()
+    }
+
+    /// The UDP checksum of `frame`, computed over the whole datagram.
+    fn full_checksum(frame: &[u8]) -> u16 {
+        let (src, dst, offset) = locate_udp(frame).unwrap();
+        let udp = &frame[offset..];
+        let mut sum = match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                sum_words(&src.octets()) + sum_words(&dst.octets())
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                sum_words(&src.octets()) + sum_words(&dst.octets())
+            }
+            _ => unreachable!(),
+        };
+        sum += u32::from(IP_PROTOCOL_UDP) + udp.len() as u32;
+        sum += sum_words(&udp[..6]) + sum_words(&udp[UDP_HEADER_LEN..]);
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        match !(sum as u16) {
+            0 => 0xffff,
+            checksum => checksum,
+        }
+    }
+
+    fn checksum(frame: &[u8]) -> u16 {
+        let (_, _, offset) = locate_udp(frame).unwrap();
+        be_u16(frame, offset + 6).unwrap()
+    }
+
+    fn set_checksum(frame: &mut [u8], checksum: u16) {
+        let (_, _, offset) = locate_udp(frame).unwrap();
+        frame[offset + 6..offset + 8].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    /// An Ethernet frame carrying a UDP datagram with a correct checksum.
+    fn udp_frame(ipv6: bool, src_port: u16, dst_port: u16, payload: &[u8]) -> Vec<u8> {
+        let udp_len = (UDP_HEADER_LEN + payload.len()) as u16;
+        let mut frame = vec![0u8; 12];
+        if ipv6 {
+            frame.extend(ETHERTYPE_IPV6.to_be_bytes());
+            let mut ip = [0u8; IPV6_HEADER_LEN];
+            ip[0] = 0x60;
+            ip[4..6].copy_from_slice(&udp_len.to_be_bytes());
+            ip[6] = IP_PROTOCOL_UDP;
+            ip[7] = 64;
+            ip[8..24].copy_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+            ip[24..40].copy_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2).octets());
+            frame.extend(ip);
+        } else {
+            frame.extend(ETHERTYPE_IPV4.to_be_bytes());
+            let mut ip = [0u8; 20];
+            ip[0] = 0x45;
+            ip[2..4].copy_from_slice(&(20 + udp_len).to_be_bytes());
+            ip[8] = 64;
+            ip[9] = IP_PROTOCOL_UDP;
+            ip[12..16].copy_from_slice(&[192, 168, 1, 2]);
+            ip[16..20].copy_from_slice(&[10, 0, 0, 1]);
+            frame.extend(ip);
+        }
+        frame.extend(src_port.to_be_bytes());
+        frame.extend(dst_port.to_be_bytes());
+        frame.extend(udp_len.to_be_bytes());
+        frame.extend([0, 0]);
+        frame.extend(payload);
+        let checksum = full_checksum(&frame);
+        set_checksum(&mut frame, checksum);
+        frame
+    }
+
+    fn remap(port: u16) -> u16 {
+        if port == 22101 { 22102 } else { port }
+    }
+
+    #[test]
+    fn rewrites_ports_and_checksum() {
+        for ipv6 in [false, true] {
+            for (src, dst) in [
+                (22101, 50000),
+                (50000, 22101),
+                (22101, 22101),
+                (50000, 50001),
+            ] {
+                let mut frame = udp_frame(ipv6, src, dst, b"odd length payload!");
+                rewrite_udp_ports(&mut frame, remap);
+
+                let datagram = parse_ethernet_udp(&frame).unwrap();
+                assert_eq!(datagram.src.port(), remap(src));
+                assert_eq!(datagram.dst.port(), remap(dst));
+                assert_eq!(checksum(&frame), full_checksum(&frame));
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_missing_ipv4_checksum() {
+        let mut frame = udp_frame(false, 22101, 50000, b"payload");
+        set_checksum(&mut frame, 0);
+        rewrite_udp_ports(&mut frame, remap);
+
+        assert_eq!(parse_ethernet_udp(&frame).unwrap().src.port(), 22102);
+        assert_eq!(checksum(&frame), 0);
+    }
+
+    #[test]
+    fn sends_zero_checksum_as_all_ones() {
+        // Pick the first payload word so that the rewritten datagram sums to
+        // negative zero, whose checksum must go out as 0xffff.
+        let rewritten = udp_frame(false, 22102, 50000, &[0; 4]);
+        let word = checksum(&rewritten).to_be_bytes();
+        let payload = [word[0], word[1], 0, 0];
+        assert_eq!(
+            full_checksum(&udp_frame(false, 22102, 50000, &payload)),
+            0xffff
+        );
+
+        let mut frame = udp_frame(false, 22101, 50000, &payload);
+        rewrite_udp_ports(&mut frame, remap);
+        assert_eq!(checksum(&frame), 0xffff);
+    }
+
+    #[test]
+    fn updates_checksum_incrementally() {
+        // RFC 1624's example, whose result is negative zero.
+        assert_eq!(update_checksum(0xdd2f, 0x5555, 0x3285), 0xffff);
+        assert_eq!(update_checksum(0x1234, 0xabcd, 0xabcd), 0x1234);
+    }
+}
@@ -1,12 +1,69 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
 use anyhow::anyhow;
 use async_trait::async_trait;
 use pcap::{Active, Capture, ConnectionStatus, Device};
-use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use regex::Regex;
+use tokio::sync::mpsc::Receiver;
+
+use crate::capture::link::{self, LinkType};
+use crate::capture::queue::{self, BACKEND_QUEUE_LEN, PacketSender, QueueStats};
+use crate::capture::{
+    CaptureBackend, CaptureConfig, CaptureError, CapturedPacket, DeviceInfo, Result,
+};
+
+/// Device selection rules compiled from a `CaptureConfig`.
+struct DeviceSelector<'a> {
+    devices: &'a [String],
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl<'a> DeviceSelector<'a> {
+    fn new(config: &'a CaptureConfig) -> Result<Self> {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| Regex::new(pattern))
+                .collect::<std::result::Result<Vec<_>, _>>()
+                .map_err(|e| CaptureError::Filter(e.into()))
+        };
+
+        Ok(Self {
+            devices: &config.devices,
+            include: compile(&config.include)?,
+            exclude: compile(&config.exclude)?,
+        })
+    }
 
-use crate::capture::{CaptureBackend, CaptureError, PORT_RANGE, Result};
+    fn matches(&self, device: &Device) -> bool {
+        let matches_any = |patterns: &[Regex]| {
+            patterns.iter().any(|pattern| {
+                pattern.is_match(&device.name)
+                    || device
+                        .desc
+                        .as_deref()
+                        .is_some_and(|desc| pattern.is_match(desc))
+            })
+        };
+
+        // Explicitly selected devices are used even if they don't report
+        // being connected.
+        let selected = if self.devices.is_empty() {
+            device.flags.connection_status == ConnectionStatus::Connected
+        } else {
+            self.devices.contains(&device.name)
+        };
+
+        selected
+            && (self.include.is_empty() || matches_any(&self.include))
+            && !matches_any(&self.exclude)
+    }
+}
 
 pub struct PcapBackend {
-    packet_rx: UnboundedReceiver<Result<Vec<u8>>>,
+    packet_rx: Receiver<Result<CapturedPacket>>,
 }
 
 impl PcapBackend {
@@ -18,11 +75,24 @@ impl PcapBackend {
         )
     }
 
-    fn should_capture_on_device(device: &Device) -> bool {
-        device.flags.connection_status == ConnectionStatus::Connected
+    pub fn list_devices() -> Result<Vec<DeviceInfo>> {
+        let devices = Device::list().map_err(|e| CaptureError::Capture {
+            has_captured: false,
+            error: e.into(),
+        })?;
+
+        Ok(devices
+            .into_iter()
+            .map(|device| DeviceInfo {
+                name: device.name,
+                desc: device.desc,
+            })
+            .collect())
     }
 
-    pub fn new() -> Result<Self> {
+    pub fn new(config: &CaptureConfig, queue_stats: Arc<QueueStats>) -> Result<Self> {
+        let selector = DeviceSelector::new(config)?;
+
         // 1. Find all devices
         let devices = Device::list().map_err(|e| CaptureError::Capture {
             has_captured: false,
@@ -42,10 +112,11 @@ impl PcapBackend {
 
         // 2. Try to set up capture on all of them (we expect some of them to fail)
         let mut successful_captures = Vec::new();
-        let filter_expression = format!("udp and portrange {}-{}", PORT_RANGE.0, PORT_RANGE.1);
+        let filter_expression = config.bpf_filter();
+        tracing::info!("Using capture filter \"{filter_expression}\"");
 
         for device in devices {
-            if !Self::should_capture_on_device(&device) {
+            if !selector.matches(&device) {
                 tracing::info!(
                     "Excluded device {} from capture",
                     PcapBackend::get_device_identifier(&device)
@@ -53,9 +124,14 @@ impl PcapBackend {
                 continue;
             }
 
-            match Self::setup_device_capture(device, &filter_expression) {
-                Ok(capture) => {
-                    successful_captures.push(capture);
+            let device_name = device.name.clone();
+            match Self::setup_device_capture(device, &filter_expression, config.filter.is_none()) {
+                Ok((device_identifier, capture, link_type)) => {
+                    successful_captures.push((device_identifier, device_name, capture, link_type));
+                }
+                Err(CaptureError::Filter(e)) if config.filter.is_some() => {
+                    // A custom filter that doesn't compile will fail on every device.
+                    return Err(CaptureError::Filter(e));
                 }
                 Err(_) => {
                     // Ignore; we probably shouldn't have captured on that device anyways
@@ -65,14 +141,13 @@ impl PcapBackend {
 
         // 3. Handle capture results
         if successful_captures.is_empty() {
-            return Err(CaptureError::Capture {
-                has_captured: false,
-                error: anyhow!("No capture device available"),
-            });
+            return Err(CaptureError::Unsupported(anyhow!(
+                "No capture device available"
+            )));
         }
 
         tracing::info!("Capturing on {} devices:", successful_captures.len());
-        for (i, (device_identifier, _)) in successful_captures.iter().enumerate() {
+        for (i, (device_identifier, _, _, _)) in successful_captures.iter().enumerate() {
             tracing::info!(
                 "Capture device {}/{}: {}",
                 i + 1,
@@ -82,11 +157,19 @@ impl PcapBackend {
         }
 
         // 4. Set up packet loops for each successful capture
-        let (packet_tx, packet_rx) = mpsc::unbounded_channel();
+        let (packet_tx, packet_rx) = queue::packet_queue(BACKEND_QUEUE_LEN, queue_stats);
 
-        for (device_identifier, capture) in successful_captures {
+        for (device_identifier, device_name, capture, link_type) in successful_captures {
             let packet_tx = packet_tx.clone();
-            std::thread::spawn(move || Self::packet_loop(capture, packet_tx, device_identifier));
+            std::thread::spawn(move || {
+                Self::packet_loop(
+                    capture,
+                    packet_tx,
+                    device_identifier,
+                    device_name,
+                    link_type,
+                )
+            });
         }
 
         Ok(Self { packet_rx })
@@ -95,7 +178,8 @@ impl PcapBackend {
     fn setup_device_capture(
         device: Device,
         filter_expression: &str,
-    ) -> Result<(String, Capture<Active>)> {
+        match_vlan: bool,
+    ) -> Result<(String, Capture<Active>, LinkType)> {
         let device_identifier = Self::get_device_identifier(&device);
 
         let mut capture = Capture::from_device(device)
@@ -110,24 +194,51 @@ impl PcapBackend {
                 error: e.into(),
             })?;
 
+        let datalink = capture.get_datalink();
+        let Some(link_type) = LinkType::from_dlt(datalink.0) else {
+            tracing::info!("Device {device_identifier} has unsupported link type {datalink:?}");
+            return Err(CaptureError::Capture {
+                has_captured: false,
+                error: anyhow!("unsupported link type {datalink:?}"),
+            });
+        };
+        tracing::debug!("Device {device_identifier} has link type {link_type:?}");
+
+        // Filters only match untagged frames unless the `vlan` keyword is used,
+        // which in turn only matches tagged ones.
+        let filter_expression = if match_vlan && link_type == LinkType::Ethernet {
+            format!("({filter_expression}) or (vlan and ({filter_expression}))")
+        } else {
+            filter_expression.to_string()
+        };
         capture
-            .filter(filter_expression, true)
+            .filter(&filter_expression, true)
             .map_err(|e| CaptureError::Filter(e.into()))?;
 
-        Ok((device_identifier, capture))
+        Ok((device_identifier, capture, link_type))
     }
 
     fn packet_loop(
         mut capture: Capture<Active>,
-        packet_tx: UnboundedSender<Result<Vec<u8>>>,
+        packet_tx: PacketSender<Result<CapturedPacket>>,
         device_identifier: String,
+        device_name: String,
+        link_type: LinkType,
     ) {
         let mut has_captured = false;
         loop {
             match capture.next_packet() {
                 Ok(packet) => {
                     has_captured = true;
-                    if packet_tx.send(Ok(packet.data.to_vec())).is_err() {
+                    let Some(data) = link::normalize(link_type, packet.data) else {
+                        tracing::trace!("Dropping non-IP frame on {device_identifier}");
+                        continue;
+                    };
+                    let timestamp = SystemTime::UNIX_EPOCH
+                        + Duration::from_secs(packet.header.ts.tv_sec as u64)
+                        + Duration::from_micros(packet.header.ts.tv_usec as u64);
+                    let packet = CapturedPacket::new(data, timestamp, Some(device_name.clone()));
+                    if packet_tx.try_send(Ok(packet)).is_err() {
                         // If the `PcapBackend` is dropped, the receiver side will be dropped, and
                         // `send` will return an error.  This is a signal to terminate this thread.
                         tracing::info!(
@@ -145,7 +256,7 @@ impl PcapBackend {
                         has_captured,
                         err
                     );
-                    let _ = packet_tx.send(Err(CaptureError::Capture {
+                    let _ = packet_tx.blocking_send(Err(CaptureError::Capture {
                         has_captured,
                         error: err.into(),
                     }));
@@ -158,7 +269,7 @@ impl PcapBackend {
 
 #[async_trait]
 impl CaptureBackend for PcapBackend {
-    async fn next_packet(&mut self) -> Result<Vec<u8>> {
+    async fn next_packet(&mut self) -> Result<CapturedPacket> {
         match self.packet_rx.recv().await {
             Some(Ok(packet)) => Ok(packet),
             Some(Err(err)) => Err(err),
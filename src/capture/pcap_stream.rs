@@ -0,0 +1,265 @@
+use std::io::ErrorKind;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result, anyhow};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::capture::CapturedPacket;
+use crate::capture::link::{self, LinkType};
+
+const MAGIC_MICROS: u32 = 0xa1b2_c3d4;
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const GLOBAL_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+
+/// Records larger than this mean the stream is corrupt or out of sync.
+const MAX_RECORD_LEN: usize = 256 * 1024;
+
+/// Reads packets from a classic pcap stream, e.g. the output of
+/// `tcpdump -w -`.  pcapng is not supported.
+pub struct PcapStreamReader<R> {
+    reader: R,
+    big_endian: bool,
+    nanos: bool,
+    link_type: LinkType,
+    interface: Option<String>,
+}
+
+impl<R: AsyncRead + Unpin> PcapStreamReader<R> {
+    /// Read the stream's global header.  Packets are reported as captured on
+    /// `interface`.
+    pub async fn new(mut reader: R, interface: Option<String>) -> Result<Self> {
+        let mut header = [0; GLOBAL_HEADER_LEN];
+        reader
+            .read_exact(&mut header)
+            .await
+            .context("can't read pcap header")?;
+
+        let magic = u32::from_le_bytes(header[0..4].try_into()?);
+        let (big_endian, nanos) = match magic {
+            MAGIC_MICROS => (false, false),
+            MAGIC_NANOS => (false, true),
+            _ if magic.swap_bytes() == MAGIC_MICROS => (true, false),
+            _ if magic.swap_bytes() == MAGIC_NANOS => (true, true),
+            _ => return Err(anyhow!("not a pcap stream (magic {magic:#010x})")),
+        };
+
+        let mut stream = Self {
+            reader,
+            big_endian,
+            nanos,
+            link_type: LinkType::Ethernet,
+            interface,
+        };
+        // The upper bits of the link type field hold FCS information.
+        let dlt = stream.u32_at(&header, 20) & 0x0fff_ffff;
+        stream.link_type = LinkType::from_dlt(dlt as i32)
+            .ok_or_else(|| anyhow!("unsupported pcap link type {dlt}"))?;
+        tracing::debug!("Reading pcap stream with link type {:?}", stream.link_type);
+
+        Ok(stream)
+    }
+
+    fn u32_at(&self, data: &[u8], offset: usize) -> u32 {
+        let bytes = data[offset..offset + 4].try_into().unwrap();
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+
+    /// The next IP packet in the stream, or `None` at the end of the stream.
+    pub async fn next_packet(&mut self) -> Result<Option<CapturedPacket>> {
+        loop {
+            let mut header = [0; RECORD_HEADER_LEN];
+            match self.reader.read_exact(&mut header).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e).context("can't read pcap record"),
+            }
+
+            let seconds = self.u32_at(&header, 0);
+            let fraction = self.u32_at(&header, 4);
+            let len = self.u32_at(&header, 8) as usize;
+            if len > MAX_RECORD_LEN {
+                return Err(anyhow!("pcap record of {len} bytes is too large"));
+            }
+
+            let mut data = vec![0; len];
+            match self.reader.read_exact(&mut data).await {
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e).context("can't read pcap record"),
+            }
+
+            let Some(data) = link::normalize(self.link_type, &data) else {
+                continue;
+            };
+            let timestamp = SystemTime::UNIX_EPOCH
+                + Duration::from_secs(seconds.into())
+                + if self.nanos {
+                    Duration::from_nanos(fraction.into())
+                } else {
+                    Duration::from_micros(fraction.into())
+                };
+
+            return Ok(Some(CapturedPacket::new(
+                data,
+                timestamp,
+                self.interface.clone(),
+            )));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture::parse::{ETHERNET_HEADER_LEN, ETHERTYPE_IPV4};
+
+    fn ethernet_frame() -> Vec<u8> {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.push(0x45);
+        frame.extend_from_slice(&[0; 19]);
+        frame
+    }
+
+    /// Builds pcap streams in either byte order.
+    struct Stream {
+        big_endian: bool,
+        data: Vec<u8>,
+    }
+
+    impl Stream {
+        fn new(big_endian: bool, magic: u32, dlt: u32) -> Self {
+            let mut stream = Self {
+                big_endian,
+                data: Vec::new(),
+            };
+            stream.u32(magic);
+            stream.u32(0x0004_0002);
+            stream.u32(0);
+            stream.u32(0);
+            stream.u32(65535);
+            stream.u32(dlt);
+            stream
+        }
+
+        fn u32(&mut self, value: u32) {
+            let bytes = if self.big_endian {
+                value.to_be_bytes()
+            } else {
+                value.to_le_bytes()
+            };
+            self.data.extend_from_slice(&bytes);
+        }
+
+        fn record(&mut self, seconds: u32, fraction: u32, packet: &[u8]) {
+            self.u32(seconds);
+            self.u32(fraction);
+            self.u32(packet.len() as u32);
+            self.u32(packet.len() as u32);
+            self.data.extend_from_slice(packet);
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_little_endian_micros() {
+        let mut stream = Stream::new(false, MAGIC_MICROS, 1);
+        stream.record(10, 500, &ethernet_frame());
+        stream.record(11, 0, &ethernet_frame());
+
+        let mut reader = PcapStreamReader::new(&stream.data[..], Some("test".to_string()))
+            .await
+            .unwrap();
+        let packet = reader.next_packet().await.unwrap().unwrap();
+        assert_eq!(packet.data, ethernet_frame());
+        assert_eq!(packet.interface.as_deref(), Some("test"));
+        assert_eq!(
+            packet.timestamp,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(10) + Duration::from_micros(500)
+        );
+        let packet = reader.next_packet().await.unwrap().unwrap();
+        assert_eq!(
+            packet.timestamp,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(11)
+        );
+        assert!(reader.next_packet().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn reads_big_endian_nanos() {
+        let mut stream = Stream::new(true, MAGIC_NANOS, 1);
+        stream.record(10, 500, &ethernet_frame());
+
+        let mut reader = PcapStreamReader::new(&stream.data[..], None).await.unwrap();
+        let packet = reader.next_packet().await.unwrap().unwrap();
+        assert_eq!(packet.data, ethernet_frame());
+        assert_eq!(
+            packet.timestamp,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(10) + Duration::from_nanos(500)
+        );
+    }
+
+    #[tokio::test]
+    async fn normalizes_link_types_and_skips_non_ip() {
+        let mut stream = Stream::new(false, MAGIC_MICROS, 101);
+        stream.record(1, 0, &[0x00, 0x01, 0x02]);
+        stream.record(2, 0, &ethernet_frame()[ETHERNET_HEADER_LEN..]);
+
+        let mut reader = PcapStreamReader::new(&stream.data[..], None).await.unwrap();
+        let packet = reader.next_packet().await.unwrap().unwrap();
+        assert_eq!(packet.data, ethernet_frame());
+        assert_eq!(
+            packet.timestamp,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(2)
+        );
+    }
+
+    #[tokio::test]
+    async fn ends_at_truncated_records() {
+        let mut stream = Stream::new(false, MAGIC_MICROS, 1);
+        stream.record(1, 0, &ethernet_frame());
+        let len = stream.data.len();
+
+        let mut reader = PcapStreamReader::new(&stream.data[..len - 4], None)
+            .await
+            .unwrap();
+        assert!(reader.next_packet().await.unwrap().is_none());
+
+        let mut reader = PcapStreamReader::new(&stream.data[..GLOBAL_HEADER_LEN + 8], None)
+            .await
+            .unwrap();
+        assert!(reader.next_packet().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_records() {
+        let mut stream = Stream::new(false, MAGIC_MICROS, 1);
+        stream.u32(1);
+        stream.u32(0);
+        stream.u32(MAX_RECORD_LEN as u32 + 1);
+        stream.u32(MAX_RECORD_LEN as u32 + 1);
+
+        let mut reader = PcapStreamReader::new(&stream.data[..], None).await.unwrap();
+        assert!(reader.next_packet().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_headers() {
+        let stream = Stream::new(false, 0x0a0d_0d0a, 1);
+        assert!(PcapStreamReader::new(&stream.data[..], None).await.is_err());
+
+        let stream = Stream::new(false, MAGIC_MICROS, 105);
+        assert!(PcapStreamReader::new(&stream.data[..], None).await.is_err());
+
+        let stream = Stream::new(false, MAGIC_MICROS, 1);
+        assert!(
+            PcapStreamReader::new(&stream.data[..10], None)
+                .await
+                .is_err()
+        );
+    }
+}
@@ -1,43 +1,68 @@
+use std::time::SystemTime;
+
+use anyhow::anyhow;
 use async_trait::async_trait;
 use futures::StreamExt;
 use futures::stream::FusedStream;
 use pktmon::filter::{PktMonFilter, TransportProtocol};
 use pktmon::{Capture, Packet};
 
-use crate::capture::{CaptureBackend, CaptureError, PORT_RANGE, Result};
+use crate::capture::{CaptureBackend, CaptureConfig, CaptureError, CapturedPacket, Result};
+
+/// Most ports a capture may filter on.  Pktmon needs a filter per port, so
+/// wide ranges would add thousands of them.
+const MAX_PORT_FILTERS: usize = 64;
 
 pub struct PktmonBackend {
     stream: Box<dyn FusedStream<Item = Packet> + Unpin + Send>,
 }
 
 impl PktmonBackend {
-    pub fn new() -> Result<Self> {
+    pub fn new(config: &CaptureConfig) -> Result<Self> {
         let mut capture = Capture::new().map_err(|e| CaptureError::Capture {
             has_captured: false,
             error: e.into(),
         })?;
 
-        let filter = PktMonFilter {
-            name: "UDP Filter".to_string(),
-            transport_protocol: Some(TransportProtocol::UDP),
-            port: PORT_RANGE.0.into(),
-            ..PktMonFilter::default()
+        // Pktmon filters match a single port, so add one per port.  Port
+        // auto-detection needs to see every UDP packet.
+        let ports: Vec<Option<u16>> = if config.auto_detect_port {
+            vec![None]
+        } else {
+            let ports = config.ports();
+            let count: usize = ports
+                .ranges()
+                .iter()
+                .map(|(start, end)| usize::from(end - start) + 1)
+                .sum();
+            if count > MAX_PORT_FILTERS {
+                return Err(CaptureError::Filter(anyhow!(
+                    "{count} ports ({ports}) exceed the pktmon limit of {MAX_PORT_FILTERS}, \
+                    use smaller port ranges or port auto-detection"
+                )));
+            }
+            ports
+                .ranges()
+                .iter()
+                .flat_map(|(start, end)| *start..=*end)
+                .map(Some)
+                .collect()
         };
 
-        capture
-            .add_filter(filter)
-            .map_err(|e| CaptureError::Filter(e.into()))?;
+        for port in ports {
+            let mut filter = PktMonFilter {
+                name: "UDP Filter".to_string(),
+                transport_protocol: Some(TransportProtocol::UDP),
+                ..PktMonFilter::default()
+            };
+            if let Some(port) = port {
+                filter.port = port.into();
+            }
 
-        let filter = PktMonFilter {
-            name: "UDP Filter".to_string(),
-            transport_protocol: Some(TransportProtocol::UDP),
-            port: PORT_RANGE.1.into(),
-            ..PktMonFilter::default()
-        };
-
-        capture
-            .add_filter(filter)
-            .map_err(|e| CaptureError::Filter(e.into()))?;
+            capture
+                .add_filter(filter)
+                .map_err(|e| CaptureError::Filter(e.into()))?;
+        }
 
         Ok(Self {
             stream: Box::new(capture.stream().unwrap().boxed().fuse()),
@@ -47,10 +72,10 @@ impl PktmonBackend {
 
 #[async_trait]
 impl CaptureBackend for PktmonBackend {
-    async fn next_packet(&mut self) -> Result<Vec<u8>> {
+    async fn next_packet(&mut self) -> Result<CapturedPacket> {
         futures::select! {
             packet = self.stream.select_next_some() => {
-                Ok(packet.payload.to_vec().clone())
+                Ok(CapturedPacket::new(packet.payload.to_vec(), SystemTime::now(), None))
             },
             complete => Err(CaptureError::CaptureClosed),
         }
@@ -0,0 +1,157 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+/// Packets queued between a backend's capture threads and the capture task.
+pub const BACKEND_QUEUE_LEN: usize = 4096;
+
+/// Packets queued between the capture task and the monitor.
+pub const MONITOR_QUEUE_LEN: usize = 1024;
+
+/// Drop and fill level counters of a bounded packet queue.  Shared between
+/// the queue's senders and the diagnostics.
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    dropped: AtomicU64,
+    high_water: AtomicUsize,
+}
+
+impl QueueStats {
+    /// Packets dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// The most packets that were waiting in the queue at once.
+    pub fn high_water(&self) -> usize {
+        self.high_water.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.dropped.store(0, Ordering::Relaxed);
+        self.high_water.store(0, Ordering::Relaxed);
+    }
+}
+
+/// The receiving end of the queue is gone.
+#[derive(Debug)]
+pub struct QueueClosed;
+
+/// Sending half of a bounded packet queue that records its drops and fill
+/// level in `QueueStats`.
+pub struct PacketSender<T> {
+    tx: Sender<T>,
+    stats: Arc<QueueStats>,
+}
+
+impl<T> Clone for PacketSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+}
+
+impl<T> PacketSender<T> {
+    fn record_len(&self) {
+        let len = self.tx.max_capacity() - self.tx.capacity();
+        self.stats.high_water.fetch_max(len, Ordering::Relaxed);
+    }
+
+    /// Queue `value` without waiting, dropping it if the queue is full.  For
+    /// live captures, where stalling the producer would only move the loss
+    /// into the kernel where it can't be counted.
+    pub fn try_send(&self, value: T) -> Result<(), QueueClosed> {
+        match self.tx.try_send(value) {
+            Ok(()) => {
+                self.record_len();
+                Ok(())
+            }
+            Err(TrySendError::Full(_)) => {
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => Err(QueueClosed),
+        }
+    }
+
+    /// Queue `value`, waiting for space.  For sources that can be slowed down
+    /// without losing data, like streams.
+    pub async fn send(&self, value: T) -> Result<(), QueueClosed> {
+        self.tx.send(value).await.map_err(|_| QueueClosed)?;
+        self.record_len();
+        Ok(())
+    }
+
+    /// Like `send`, for use outside of the async runtime.
+    #[cfg_attr(not(any(feature = "pcap", target_os = "linux")), allow(dead_code))]
+    pub fn blocking_send(&self, value: T) -> Result<(), QueueClosed> {
+        self.tx.blocking_send(value).map_err(|_| QueueClosed)?;
+        self.record_len();
+        Ok(())
+    }
+
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+}
+
+/// Create a bounded packet queue holding up to `len` packets.
+pub fn packet_queue<T>(len: usize, stats: Arc<QueueStats>) -> (PacketSender<T>, Receiver<T>) {
+    let (tx, rx) = mpsc::channel(len);
+    (PacketSender { tx, stats }, rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_drops_when_full() {
+        let stats = Arc::new(QueueStats::default());
+        let (tx, mut rx) = packet_queue(2, stats.clone());
+
+        for i in 0..5 {
+            tx.try_send(i).unwrap();
+        }
+        assert_eq!(stats.dropped(), 3);
+        assert_eq!(stats.high_water(), 2);
+        assert_eq!(rx.try_recv().unwrap(), 0);
+        assert_eq!(rx.try_recv().unwrap(), 1);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn tracks_high_water() {
+        let stats = Arc::new(QueueStats::default());
+        let (tx, mut rx) = packet_queue(8, stats.clone());
+
+        for i in 0..3 {
+            tx.blocking_send(i).unwrap();
+        }
+        while rx.try_recv().is_ok() {}
+        tx.try_send(3).unwrap();
+        assert_eq!(stats.high_water(), 3);
+        assert_eq!(stats.dropped(), 0);
+
+        stats.reset();
+        assert_eq!(stats.high_water(), 0);
+        tx.try_send(4).unwrap();
+        assert_eq!(stats.high_water(), 2);
+    }
+
+    #[test]
+    fn reports_closed_queue() {
+        let stats = Arc::new(QueueStats::default());
+        let (tx, rx) = packet_queue(1, stats.clone());
+
+        drop(rx);
+        assert!(tx.try_send(0).is_err());
+        assert!(tx.blocking_send(0).is_err());
+        assert_eq!(stats.dropped(), 0);
+    }
+}
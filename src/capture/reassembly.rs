@@ -0,0 +1,487 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use crate::capture::CapturedPacket;
+use crate::capture::parse::{
+    ETHERNET_HEADER_LEN, ETHERTYPE_IPV4, ETHERTYPE_IPV6, IP_PROTOCOL_IPV6_FRAGMENT,
+    IPV4_FRAGMENT_MASK, IPV6_HEADER_LEN, be_u16, be_u32,
+};
+
+/// Incomplete datagrams are dropped after this long.
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Upper bound on datagrams being reassembled at once so a stream of stray
+/// fragments can't grow memory without limit.
+const MAX_PENDING_DATAGRAMS: usize = 64;
+
+const IPV6_FRAGMENT_HEADER_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum IpVersion {
+    V4,
+    V6,
+}
+
+/// Identifies the fragments of a single datagram.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct FragmentKey {
+    src: IpAddr,
+    dst: IpAddr,
+    protocol: u8,
+    id: u32,
+}
+
+struct Fragment<'a> {
+    key: FragmentKey,
+    version: IpVersion,
+    offset: usize,
+    more_fragments: bool,
+    /// Ethernet and IP headers, without any IPv6 fragment header.
+    headers: &'a [u8],
+    payload: &'a [u8],
+}
+
+fn parse_fragment(frame: &[u8]) -> Option<Fragment<'_>> {
+    let ip = frame.get(ETHERNET_HEADER_LEN..)?;
+
+    match be_u16(frame, 12)? {
+        ETHERTYPE_IPV4 => {
+            let flags_offset = be_u16(ip, 6)?;
+            if flags_offset & IPV4_FRAGMENT_MASK == 0 {
+                return None;
+            }
+            let header_len = usize::from(*ip.first()? & 0xf) * 4;
+            let total_len = usize::from(be_u16(ip, 2)?);
+            let src: [u8; 4] = ip.get(12..16)?.try_into().ok()?;
+            let dst: [u8; 4] = ip.get(16..20)?.try_into().ok()?;
+
+            Some(Fragment {
+                key: FragmentKey {
+                    src: Ipv4Addr::from(src).into(),
+                    dst: Ipv4Addr::from(dst).into(),
+                    protocol: *ip.get(9)?,
+                    id: be_u16(ip, 4)?.into(),
+                },
+                version: IpVersion::V4,
+                offset: usize::from(flags_offset & 0x1fff) * 8,
+                more_fragments: flags_offset & 0x2000 != 0,
+                headers: frame.get(..ETHERNET_HEADER_LEN + header_len)?,
+                payload: ip.get(header_len..total_len.min(ip.len()))?,
+            })
+        }
+        ETHERTYPE_IPV6 => {
+            // Only fragment headers directly following the base header are
+            // supported.
+            if ip.get(6)? != &IP_PROTOCOL_IPV6_FRAGMENT {
+                return None;
+            }
+            let payload_len = usize::from(be_u16(ip, 4)?);
+            let fragment_header =
+                ip.get(IPV6_HEADER_LEN..IPV6_HEADER_LEN + IPV6_FRAGMENT_HEADER_LEN)?;
+            let offset_flags = be_u16(fragment_header, 2)?;
+            let src: [u8; 16] = ip.get(8..24)?.try_into().ok()?;
+            let dst: [u8; 16] = ip.get(24..40)?.try_into().ok()?;
+
+            Some(Fragment {
+                key: FragmentKey {
+                    src: Ipv6Addr::from(src).into(),
+                    dst: Ipv6Addr::from(dst).into(),
+                    protocol: fragment_header[0],
+                    id: be_u32(fragment_header, 4)?,
+                },
+                version: IpVersion::V6,
+                offset: usize::from(offset_flags >> 3) * 8,
+                more_fragments: offset_flags & 1 != 0,
+                headers: frame.get(..ETHERNET_HEADER_LEN + IPV6_HEADER_LEN)?,
+                payload: ip.get(
+                    IPV6_HEADER_LEN + IPV6_FRAGMENT_HEADER_LEN
+                        ..(IPV6_HEADER_LEN + payload_len).min(ip.len()),
+                )?,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn ipv4_header_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+struct PendingDatagram {
+    started: Instant,
+    version: IpVersion,
+    protocol: u8,
+    /// Headers of the first fragment, once it has arrived.
+    headers: Option<Vec<u8>>,
+    /// Fragment payloads keyed by their offset in the datagram.
+    fragments: BTreeMap<usize, Vec<u8>>,
+    /// Known once the last fragment has arrived.
+    total_len: Option<usize>,
+}
+
+impl PendingDatagram {
+    /// Build the unfragmented frame if every fragment has arrived.
+    fn reassemble(&self) -> Option<Vec<u8>> {
+        let total_len = self.total_len?;
+        let headers = self.headers.as_ref()?;
+
+        let mut payload = Vec::with_capacity(total_len);
+        for (offset, data) in &self.fragments {
+            if *offset > payload.len() {
+                // Still missing a fragment.
+                return None;
+            }
+            // Skip any overlap with fragments already copied.
+            let overlap = payload.len() - offset;
+            if overlap < data.len() {
+                payload.extend_from_slice(&data[overlap..]);
+            }
+        }
+        if payload.len() < total_len {
+            return None;
+        }
+        payload.truncate(total_len);
+
+        let mut frame = headers.clone();
+        let ip = &mut frame[ETHERNET_HEADER_LEN..];
+        match self.version {
+            IpVersion::V4 => {
+                let len = u16::try_from(ip.len() + payload.len()).ok()?;
+                ip[2..4].copy_from_slice(&len.to_be_bytes());
+                ip[6..8].copy_from_slice(&[0, 0]);
+                ip[10..12].copy_from_slice(&[0, 0]);
+                let checksum = ipv4_header_checksum(ip);
+                ip[10..12].copy_from_slice(&checksum.to_be_bytes());
+            }
+            IpVersion::V6 => {
+                let len = u16::try_from(payload.len()).ok()?;
+                ip[4..6].copy_from_slice(&len.to_be_bytes());
+                ip[6] = self.protocol;
+            }
+        }
+        frame.extend_from_slice(&payload);
+
+        Some(frame)
+    }
+}
+
+/// Reassembles fragmented IPv4 and IPv6 datagrams so the sniffer sees whole
+/// UDP datagrams.
+pub struct Reassembler {
+    pending: HashMap<FragmentKey, PendingDatagram>,
+    reassembled: u64,
+    expired: u64,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self {
+            pending: HashMap::new(),
+            reassembled: 0,
+            expired: 0,
+        }
+    }
+
+    /// Number of datagrams reassembled so far.
+    pub fn reassembled(&self) -> u64 {
+        self.reassembled
+    }
+
+    /// Number of datagrams dropped because not all fragments arrived.
+    pub fn expired(&self) -> u64 {
+        self.expired
+    }
+
+    /// Returns unfragmented packets unchanged, `None` while a datagram is
+    /// incomplete, and the reassembled packet once its last fragment arrives.
+    pub fn process(&mut self, packet: CapturedPacket) -> Option<CapturedPacket> {
+        let Some(fragment) = parse_fragment(&packet.data) else {
+            return Some(packet);
+        };
+
+        let now = Instant::now();
+        let before = self.pending.len();
+        self.pending
+            .retain(|_, pending| now.duration_since(pending.started) < REASSEMBLY_TIMEOUT);
+        self.expired += (before - self.pending.len()) as u64;
+
+        if !self.pending.contains_key(&fragment.key) && self.pending.len() >= MAX_PENDING_DATAGRAMS
+        {
+            tracing::debug!("Too many datagrams being reassembled, dropping fragment");
+            return None;
+        }
+
+        let key = fragment.key.clone();
+        let pending = self
+            .pending
+            .entry(key.clone())
+            .or_insert_with(|| PendingDatagram {
+                started: now,
+                version: fragment.version,
+                protocol: fragment.key.protocol,
+                headers: None,
+                fragments: BTreeMap::new(),
+                total_len: None,
+            });
+        if fragment.offset == 0 {
+            pending.headers = Some(fragment.headers.to_vec());
+        }
+        if !fragment.more_fragments {
+            pending.total_len = Some(fragment.offset + fragment.payload.len());
+        }
+        pending
+            .fragments
+            .insert(fragment.offset, fragment.payload.to_vec());
+
+        let frame = pending.reassemble()?;
+        self.pending.remove(&key);
+        self.reassembled += 1;
+        tracing::trace!("Reassembled {} byte datagram", frame.len());
+
+        Some(CapturedPacket::new(
+            frame,
+            packet.timestamp,
+            packet.interface,
+        ))
+    }
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::capture::parse::{IP_PROTOCOL_UDP, parse_ethernet_udp};
+
+    /// A UDP datagram from 10.0.0.1:22101 to 10.0.0.2:5000 with a `len` byte
+    /// payload.
+    fn udp_datagram(len: usize) -> Vec<u8> {
+        let mut datagram = Vec::new();
+        datagram.extend_from_slice(&22101u16.to_be_bytes());
+        datagram.extend_from_slice(&5000u16.to_be_bytes());
+        datagram.extend_from_slice(&((8 + len) as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend((0..len).map(|i| i as u8));
+        datagram
+    }
+
+    fn ipv4_fragment(id: u16, offset: usize, more: bool, payload: &[u8]) -> CapturedPacket {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        let flags_offset = (offset / 8) as u16 | if more { 0x2000 } else { 0 };
+        frame.extend_from_slice(&[0x45, 0]);
+        frame.extend_from_slice(&((20 + payload.len()) as u16).to_be_bytes());
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.extend_from_slice(&flags_offset.to_be_bytes());
+        frame.extend_from_slice(&[64, IP_PROTOCOL_UDP, 0, 0]);
+        frame.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        frame.extend_from_slice(payload);
+        CapturedPacket::new(frame, SystemTime::UNIX_EPOCH, None)
+    }
+
+    fn ipv6_fragment(id: u32, offset: usize, more: bool, payload: &[u8]) -> CapturedPacket {
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&ETHERTYPE_IPV6.to_be_bytes());
+        frame.extend_from_slice(&[0x60, 0, 0, 0]);
+        frame.extend_from_slice(&((IPV6_FRAGMENT_HEADER_LEN + payload.len()) as u16).to_be_bytes());
+        frame.extend_from_slice(&[IP_PROTOCOL_IPV6_FRAGMENT, 64]);
+        frame.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        frame.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        let offset_flags = (((offset / 8) as u16) << 3) | u16::from(more);
+        frame.extend_from_slice(&[IP_PROTOCOL_UDP, 0]);
+        frame.extend_from_slice(&offset_flags.to_be_bytes());
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.extend_from_slice(payload);
+        CapturedPacket::new(frame, SystemTime::UNIX_EPOCH, None)
+    }
+
+    fn assert_ipv4_datagram(packet: &CapturedPacket, datagram: &[u8]) {
+        let ip = &packet.data[ETHERNET_HEADER_LEN..];
+        assert_eq!(&ip[20..], datagram);
+        assert_eq!(be_u16(ip, 2), Some((20 + datagram.len()) as u16));
+        assert_eq!(be_u16(ip, 6), Some(0));
+        assert_eq!(ipv4_header_checksum(&ip[..20]), 0);
+        let udp = parse_ethernet_udp(&packet.data).unwrap();
+        assert_eq!(udp.src.port(), 22101);
+        assert_eq!(udp.payload, &datagram[8..]);
+    }
+
+    #[test]
+    fn passes_unfragmented_packets_through() {
+        let mut reassembler = Reassembler::new();
+        let packet = ipv4_fragment(1, 0, false, &udp_datagram(16));
+        let data = packet.data.clone();
+        assert_eq!(reassembler.process(packet).unwrap().data, data);
+        assert_eq!(reassembler.reassembled(), 0);
+    }
+
+    #[test]
+    fn reassembles_ipv4_in_order() {
+        let datagram = udp_datagram(24);
+        let mut reassembler = Reassembler::new();
+        assert!(
+            reassembler
+                .process(ipv4_fragment(1, 0, true, &datagram[..16]))
+                .is_none()
+        );
+        let packet = reassembler
+            .process(ipv4_fragment(1, 16, false, &datagram[16..]))
+            .unwrap();
+        assert_ipv4_datagram(&packet, &datagram);
+        assert_eq!(reassembler.reassembled(), 1);
+    }
+
+    #[test]
+    fn reassembles_ipv4_out_of_order() {
+        let datagram = udp_datagram(32);
+        let mut reassembler = Reassembler::new();
+        assert!(
+            reassembler
+                .process(ipv4_fragment(1, 24, false, &datagram[24..]))
+                .is_none()
+        );
+        assert!(
+            reassembler
+                .process(ipv4_fragment(1, 8, true, &datagram[8..24]))
+                .is_none()
+        );
+        let packet = reassembler
+            .process(ipv4_fragment(1, 0, true, &datagram[..8]))
+            .unwrap();
+        assert_ipv4_datagram(&packet, &datagram);
+    }
+
+    #[test]
+    fn reassembles_overlapping_fragments() {
+        let datagram = udp_datagram(24);
+        let mut reassembler = Reassembler::new();
+        assert!(
+            reassembler
+                .process(ipv4_fragment(1, 0, true, &datagram[..24]))
+                .is_none()
+        );
+        let packet = reassembler
+            .process(ipv4_fragment(1, 8, false, &datagram[8..]))
+            .unwrap();
+        assert_ipv4_datagram(&packet, &datagram);
+    }
+
+    #[test]
+    fn waits_for_missing_fragments() {
+        let datagram = udp_datagram(24);
+        let mut reassembler = Reassembler::new();
+        assert!(
+            reassembler
+                .process(ipv4_fragment(1, 0, true, &datagram[..8]))
+                .is_none()
+        );
+        assert!(
+            reassembler
+                .process(ipv4_fragment(1, 24, false, &datagram[24..]))
+                .is_none()
+        );
+        // A fragment of another datagram doesn't fill the gap.
+        assert!(
+            reassembler
+                .process(ipv4_fragment(2, 8, true, &datagram[8..24]))
+                .is_none()
+        );
+        assert_eq!(reassembler.reassembled(), 0);
+    }
+
+    #[test]
+    fn drops_oversized_datagrams() {
+        let payload = vec![0; 32768];
+        let mut reassembler = Reassembler::new();
+        assert!(
+            reassembler
+                .process(ipv4_fragment(1, 0, true, &payload))
+                .is_none()
+        );
+        // The reassembled datagram wouldn't fit in the IPv4 length field.
+        assert!(
+            reassembler
+                .process(ipv4_fragment(1, 32768, false, &payload))
+                .is_none()
+        );
+        assert_eq!(reassembler.reassembled(), 0);
+    }
+
+    #[test]
+    fn limits_pending_datagrams() {
+        let datagram = udp_datagram(16);
+        let mut reassembler = Reassembler::new();
+        for id in 0..MAX_PENDING_DATAGRAMS as u16 {
+            assert!(
+                reassembler
+                    .process(ipv4_fragment(id, 0, true, &datagram[..8]))
+                    .is_none()
+            );
+        }
+
+        let id = MAX_PENDING_DATAGRAMS as u16;
+        assert!(
+            reassembler
+                .process(ipv4_fragment(id, 0, true, &datagram[..8]))
+                .is_none()
+        );
+        assert!(
+            reassembler
+                .process(ipv4_fragment(id, 8, false, &datagram[8..]))
+                .is_none()
+        );
+
+        // Datagrams already being reassembled can still complete.
+        let packet = reassembler
+            .process(ipv4_fragment(0, 8, false, &datagram[8..]))
+            .unwrap();
+        assert_ipv4_datagram(&packet, &datagram);
+    }
+
+    #[test]
+    fn reassembles_ipv6() {
+        let datagram = udp_datagram(24);
+        let mut reassembler = Reassembler::new();
+        assert!(
+            reassembler
+                .process(ipv6_fragment(7, 16, false, &datagram[16..]))
+                .is_none()
+        );
+        let packet = reassembler
+            .process(ipv6_fragment(7, 0, true, &datagram[..16]))
+            .unwrap();
+
+        let ip = &packet.data[ETHERNET_HEADER_LEN..];
+        assert_eq!(ip[6], IP_PROTOCOL_UDP);
+        assert_eq!(be_u16(ip, 4), Some(datagram.len() as u16));
+        assert_eq!(&ip[IPV6_HEADER_LEN..], &datagram[..]);
+        let udp = parse_ethernet_udp(&packet.data).unwrap();
+        assert_eq!(udp.payload, &datagram[8..]);
+    }
+
+    #[test]
+    fn ignores_truncated_fragments() {
+        let packet = ipv4_fragment(1, 0, true, &udp_datagram(8));
+        let truncated = CapturedPacket::new(
+            packet.data[..ETHERNET_HEADER_LEN + 8].to_vec(),
+            SystemTime::UNIX_EPOCH,
+            None,
+        );
+        let mut reassembler = Reassembler::new();
+        assert!(reassembler.process(truncated).is_some());
+    }
+}
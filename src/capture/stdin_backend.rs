@@ -0,0 +1,92 @@
+use std::sync::{Arc, OnceLock};
+
+use async_trait::async_trait;
+use tokio::io::BufReader;
+use tokio::sync::Mutex;
+use tokio::sync::mpsc::Receiver;
+
+use crate::capture::pcap_stream::PcapStreamReader;
+use crate::capture::queue::{self, BACKEND_QUEUE_LEN, PacketSender, QueueStats};
+use crate::capture::{CaptureBackend, CaptureError, CapturedPacket, Result};
+
+type PacketReceiver = Mutex<Receiver<Result<CapturedPacket>>>;
+
+/// Stdin can only be read once, so a single reader task outlives the backends
+/// created each time capture starts.
+static PACKET_RX: OnceLock<PacketReceiver> = OnceLock::new();
+
+/// Reads a pcap stream from stdin, e.g. `tcpdump -w - | irminsul --stdin`.
+pub struct StdinBackend {
+    packet_rx: &'static PacketReceiver,
+}
+
+impl StdinBackend {
+    pub fn new(queue_stats: Arc<QueueStats>) -> Result<Self> {
+        let packet_rx = PACKET_RX.get_or_init(|| {
+            let (packet_tx, packet_rx) = queue::packet_queue(BACKEND_QUEUE_LEN, queue_stats);
+            tokio::spawn(Self::read_loop(packet_tx));
+            Mutex::new(packet_rx)
+        });
+
+        // Drop whatever arrived while capture was stopped.
+        if let Ok(mut packet_rx) = packet_rx.try_lock() {
+            while packet_rx.try_recv().is_ok() {}
+        }
+
+        Ok(Self { packet_rx })
+    }
+
+    async fn read_loop(packet_tx: PacketSender<Result<CapturedPacket>>) {
+        let stdin = BufReader::new(tokio::io::stdin());
+        let mut reader = match PcapStreamReader::new(stdin, Some("stdin".to_string())).await {
+            Ok(reader) => reader,
+            Err(e) => {
+                let _ = packet_tx
+                    .send(Err(CaptureError::Capture {
+                        has_captured: false,
+                        error: e,
+                    }))
+                    .await;
+                return;
+            }
+        };
+        tracing::info!("Reading pcap stream from stdin");
+
+        let mut has_captured = false;
+        loop {
+            match reader.next_packet().await {
+                Ok(Some(packet)) => {
+                    has_captured = true;
+                    if packet_tx.send(Ok(packet)).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => {
+                    tracing::info!("End of pcap stream on stdin");
+                    let _ = packet_tx.send(Err(CaptureError::CaptureClosed)).await;
+                    break;
+                }
+                Err(error) => {
+                    let _ = packet_tx
+                        .send(Err(CaptureError::Capture {
+                            has_captured,
+                            error,
+                        }))
+                        .await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl CaptureBackend for StdinBackend {
+    async fn next_packet(&mut self) -> Result<CapturedPacket> {
+        match self.packet_rx.lock().await.recv().await {
+            Some(Ok(packet)) => Ok(packet),
+            Some(Err(err)) => Err(err),
+            None => Err(CaptureError::CaptureClosed),
+        }
+    }
+}
@@ -0,0 +1,98 @@
+//! Machine-readable progress events on stdout, for wrapping Irminsul in
+//! scripts.  Enabled with `--events ndjson`, which writes one JSON object per
+//! line.
+
+use std::fmt::Debug;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use chrono::Local;
+use clap::ValueEnum;
+use serde::Serialize;
+use tracing::field::{Field, Visit};
+use tracing::{Level, Subscriber};
+use tracing_subscriber::Layer;
+use tracing_subscriber::layer::Context;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum EventFormat {
+    /// Newline-delimited JSON.
+    Ndjson,
+}
+
+pub fn enable(format: EventFormat) {
+    match format {
+        EventFormat::Ndjson => ENABLED.store(true, Ordering::Relaxed),
+    }
+}
+
+fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    CaptureStarted,
+    CaptureStopped,
+    CaptureRestarting { reason: &'a str },
+    CaptureFailed { reason: &'a str },
+    DataUpdated { category: &'a str, count: usize },
+    WishUrl { url: &'a str },
+    Error { target: &'a str, message: &'a str },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: String,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Write `event` to stdout if events are enabled.
+pub fn emit(event: Event<'_>) {
+    if !enabled() {
+        return;
+    }
+
+    let line = Line {
+        time: Local::now().to_rfc3339(),
+        event: &event,
+    };
+    let Ok(json) = serde_json::to_string(&line) else {
+        return;
+    };
+    let mut stdout = std::io::stdout().lock();
+    let _ = writeln!(stdout, "{json}");
+    let _ = stdout.flush();
+}
+
+/// Turns error logs into `error` events.
+pub struct ErrorEventLayer;
+
+impl<S: Subscriber> Layer<S> for ErrorEventLayer {
+    fn on_event(&self, event: &tracing::Event<'_>, _ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::ERROR || !enabled() {
+            return;
+        }
+
+        let mut visitor = MessageVisitor(String::new());
+        event.record(&mut visitor);
+        emit(Event::Error {
+            target: event.metadata().target(),
+            message: &visitor.0,
+        });
+    }
+}
+
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{value:?}");
+        }
+    }
+}
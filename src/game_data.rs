@@ -0,0 +1,310 @@
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anime_game_data::AnimeGameData;
+use anyhow::{Context, Result, anyhow};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use reqwest::{StatusCode, header};
+use serde::{Deserialize, Serialize};
+
+use crate::APP_ID;
+
+static EMBEDDED_DATABASE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/game_data.gz"));
+
+/// Identifies the embedded copy, see `build.rs`.
+const EMBEDDED_DATABASE_VERSION: &str = env!("EMBEDDED_GAME_DATA_VERSION");
+
+const CACHE_DATA_FILE: &str = "game_data.gz";
+const CACHE_METADATA_FILE: &str = "game_data_source.json";
+const UPSTREAM_CACHE_FILE: &str = "upstream_cache.json";
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(30);
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How long a skipped update without a known version stays skipped.
+const SKIP_DURATION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Where game data is loaded or runtime game data updates are fetched from.
+#[derive(Clone, Debug)]
+pub enum GameDataSource {
+    /// Load an `AnimeGameData` JSON or gzipped JSON file from disk, skipping
+    /// update checks entirely.
+    File(PathBuf),
+
+    /// Use `anime-game-data`'s own update mechanism, the same one `build.rs`
+    /// uses to produce the embedded copy.
+    Upstream,
+
+    /// Fetch a gzipped `AnimeGameData` blob (the format of the embedded copy)
+    /// from a URL.
+    Url(String),
+}
+
+impl GameDataSource {
+    pub fn new(url: Option<String>, path: Option<PathBuf>) -> Self {
+        match (path, url) {
+            (Some(path), _) => Self::File(path),
+            (None, Some(url)) => Self::Url(url),
+            (None, None) => Self::Upstream,
+        }
+    }
+}
+
+/// Describes the contents of the cached copy in the storage dir.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+struct CacheMetadata {
+    url: Option<String>,
+    version: Option<String>,
+    /// `EMBEDDED_DATABASE_VERSION` of the build that downloaded the cached
+    /// copy.  Missing from caches written by earlier versions.
+    #[serde(default)]
+    embedded_version: Option<String>,
+    /// The update the user last chose not to download.
+    #[serde(default)]
+    skipped: Option<SkippedUpdate>,
+}
+
+/// An update the user chose not to download.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+struct SkippedUpdate {
+    /// `None` for upstream updates.
+    url: Option<String>,
+    version: Option<String>,
+    /// When it was skipped, in seconds since the Unix epoch.
+    time: u64,
+}
+
+impl CacheMetadata {
+    /// Whether the user skipped the update from `url` (`None` for upstream) to
+    /// `version`.  Updates with no known version are skipped for
+    /// `SKIP_DURATION`.
+    fn is_skipped(&self, url: Option<&String>, version: Option<&String>) -> bool {
+        self.skipped.as_ref().is_some_and(|skipped| {
+            skipped.url.as_ref() == url
+                && match (&skipped.version, version) {
+                    (Some(skipped), Some(version)) => skipped == version,
+                    _ => now() < skipped.time + SKIP_DURATION.as_secs(),
+                }
+        })
+    }
+}
+
+/// A newer version of the game data which has been found but not downloaded.
+pub enum PendingUpdate {
+    Upstream(AnimeGameData),
+    Url {
+        url: String,
+        version: Option<String>,
+    },
+}
+
+impl PendingUpdate {
+    /// Don't offer this update again, see `CacheMetadata::is_skipped`.
+    pub fn skip(&self) -> Result<()> {
+        let (url, version) = match self {
+            Self::Upstream(_) => (None, None),
+            Self::Url { url, version } => (Some(url.clone()), version.clone()),
+        };
+        save_metadata(&CacheMetadata {
+            skipped: Some(SkippedUpdate {
+                url,
+                version,
+                time: now(),
+            }),
+            ..load_metadata().unwrap_or_default()
+        })
+    }
+}
+
+fn cache_dir() -> Result<PathBuf> {
+    let mut dir = eframe::storage_dir(APP_ID).context("Storage dir not found")?;
+    dir.push("game_data");
+    fs::create_dir_all(&dir).with_context(|| format!("can't create directory {dir:?}"))?;
+    Ok(dir)
+}
+
+fn load_metadata() -> Option<CacheMetadata> {
+    let path = cache_dir().ok()?.join(CACHE_METADATA_FILE);
+    let data = fs::read(path).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+fn save_metadata(metadata: &CacheMetadata) -> Result<()> {
+    let path = cache_dir()?.join(CACHE_METADATA_FILE);
+    let file = File::create(&path).with_context(|| format!("can't create file {path:?}"))?;
+    serde_json::to_writer(BufWriter::new(file), metadata)?;
+    Ok(())
+}
+
+pub fn has_cached() -> bool {
+    cache_dir().is_ok_and(|dir| dir.join(CACHE_DATA_FILE).exists())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or_default()
+}
+
+fn http_client(timeout: Duration) -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(timeout)
+        .build()?)
+}
+
+/// Whether the cached copy was downloaded as an update to this build's
+/// embedded copy.  After an app update the embedded copy may be newer than
+/// the cached one, so the cache is only used once it's downloaded again.
+fn cache_is_newer(metadata: &CacheMetadata) -> bool {
+    metadata.embedded_version.as_deref() == Some(EMBEDDED_DATABASE_VERSION)
+}
+
+pub fn load_embedded() -> Result<AnimeGameData> {
+    let reader = GzDecoder::new(EMBEDDED_DATABASE);
+    Ok(AnimeGameData::new_from_reader(reader)?)
+}
+
+/// Load the copy of the game data downloaded by a previous runtime update,
+/// unless the embedded copy is newer.
+pub fn load_cached() -> Result<Option<AnimeGameData>> {
+    let path = cache_dir()?.join(CACHE_DATA_FILE);
+    if !path.exists() {
+        return Ok(None);
+    }
+    if !load_metadata().is_some_and(|metadata| cache_is_newer(&metadata)) {
+        tracing::info!("Cached game data was downloaded for other embedded data");
+        return Ok(None);
+    }
+
+    let file = File::open(&path).with_context(|| format!("can't open file {path:?}"))?;
+    let db = AnimeGameData::new_from_reader(GzDecoder::new(file))
+        .with_context(|| format!("can't load game data from {path:?}"))?;
+    Ok(Some(db))
+}
+
+/// Load game data from a user supplied file.  Gzipped files are detected by
+/// their magic bytes rather than their extension.
+pub fn load_file(path: &Path) -> Result<AnimeGameData> {
+    let mut file = File::open(path).with_context(|| format!("can't open file {path:?}"))?;
+    let mut magic = [0u8; 2];
+    let is_gzip = file.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b];
+    let file = File::open(path).with_context(|| format!("can't open file {path:?}"))?;
+
+    let db = if is_gzip {
+        AnimeGameData::new_from_reader(GzDecoder::new(BufReader::new(file)))
+    } else {
+        AnimeGameData::new_from_reader(BufReader::new(file))
+    }
+    .with_context(|| format!("can't load game data from {path:?}"))?;
+    Ok(db)
+}
+
+pub async fn check_for_update(source: &GameDataSource) -> Result<Option<PendingUpdate>> {
+    let metadata = load_metadata().unwrap_or_default();
+    match source {
+        GameDataSource::File(_) => Ok(None),
+        GameDataSource::Upstream => {
+            let cache_path = cache_dir()?.join(UPSTREAM_CACHE_FILE);
+            let mut db = AnimeGameData::new_with_cache(&cache_path);
+            let needs_update = tokio::time::timeout(CHECK_TIMEOUT, db.needs_update())
+                .await
+                .context("Timed out checking for game data updates")??;
+            let cached = has_cached() && metadata.url.is_none() && cache_is_newer(&metadata);
+            if (needs_update || !cached) && !metadata.is_skipped(None, None) {
+                return Ok(Some(PendingUpdate::Upstream(db)));
+            }
+            Ok(None)
+        }
+        GameDataSource::Url(url) => {
+            let response = http_client(CHECK_TIMEOUT)?
+                .head(url)
+                .send()
+                .await
+                .with_context(|| format!("Failed to check {url} for game data"))?
+                .error_for_status()?;
+            let version = response_version(response.headers());
+
+            let up_to_date = has_cached()
+                && metadata.url.as_ref() == Some(url)
+                && cache_is_newer(&metadata)
+                && (version.is_none() || metadata.version == version);
+            if up_to_date {
+                tracing::info!("game data from {url} is up to date");
+                return Ok(None);
+            }
+            if metadata.is_skipped(Some(url), version.as_ref()) {
+                tracing::info!("Skipping game data update from {url}");
+                return Ok(None);
+            }
+
+            Ok(Some(PendingUpdate::Url {
+                url: url.clone(),
+                version,
+            }))
+        }
+    }
+}
+
+/// Download `update`, store it in the cache, and return the loaded data.
+pub async fn download_update(update: PendingUpdate) -> Result<AnimeGameData> {
+    let path = cache_dir()?.join(CACHE_DATA_FILE);
+    match update {
+        PendingUpdate::Upstream(mut db) => {
+            tracing::info!("downloading game data from upstream");
+            tokio::time::timeout(DOWNLOAD_TIMEOUT, db.update())
+                .await
+                .context("Timed out downloading game data")??;
+            let file =
+                File::create(&path).with_context(|| format!("can't create file {path:?}"))?;
+            db.save_to_writer(GzEncoder::new(file, Compression::best()))?;
+            save_metadata(&CacheMetadata {
+                embedded_version: Some(EMBEDDED_DATABASE_VERSION.to_string()),
+                ..Default::default()
+            })?;
+            Ok(db)
+        }
+        PendingUpdate::Url { url, version } => {
+            tracing::info!("downloading game data from {url}");
+            let response = http_client(DOWNLOAD_TIMEOUT)?
+                .get(&url)
+                .send()
+                .await
+                .with_context(|| format!("Failed to download game data from {url}"))?;
+            if response.status() != StatusCode::OK {
+                return Err(anyhow!(
+                    "Failed to download game data from {url}: {}",
+                    response.status()
+                ));
+            }
+            let version = response_version(response.headers()).or(version);
+            let data = response.bytes().await?;
+
+            // Make sure the data is usable before replacing the cached copy.
+            let db = AnimeGameData::new_from_reader(GzDecoder::new(&data[..]))
+                .with_context(|| format!("Invalid game data downloaded from {url}"))?;
+            fs::write(&path, &data).with_context(|| format!("can't write file {path:?}"))?;
+            save_metadata(&CacheMetadata {
+                url: Some(url),
+                version,
+                embedded_version: Some(EMBEDDED_DATABASE_VERSION.to_string()),
+                skipped: None,
+            })?;
+            Ok(db)
+        }
+    }
+}
+
+fn response_version(headers: &header::HeaderMap) -> Option<String> {
+    headers
+        .get(header::ETAG)
+        .or_else(|| headers.get(header::LAST_MODIFIED))
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
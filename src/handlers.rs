@@ -0,0 +1,197 @@
+//! Handlers for decoded game commands.
+//!
+//! Every command the sniffer decodes is offered to each registered
+//! `PacketHandler`.  Handlers pick out the commands they understand and feed
+//! them into the player data, and may add what they collected to exports.
+//! New handlers are added in `HandlerRegistry::with_default_handlers`, and
+//! publish what they find under their own category with
+//! `HandlerContext::publish`.
+
+use auto_artifactarium::{
+    GameCommand, matches_achievement_packet, matches_avatar_packet, matches_item_packet,
+};
+
+use crate::DataUpdated;
+use crate::events::{self, Event};
+use crate::good::Good;
+use crate::packet_log::proto;
+use crate::player_data::{ExportSettings, PlayerData};
+
+/// Categories the built-in handlers publish under.
+pub const ITEMS: &str = "items";
+pub const AVATARS: &str = "avatars";
+pub const ACHIEVEMENTS: &str = "achievements";
+
+/// What a handler may update while handling a command.
+pub struct HandlerContext<'a> {
+    pub player_data: &'a mut PlayerData,
+    updated: &'a mut DataUpdated,
+}
+
+impl<'a> HandlerContext<'a> {
+    pub fn new(player_data: &'a mut PlayerData, updated: &'a mut DataUpdated) -> Self {
+        Self {
+            player_data,
+            updated,
+        }
+    }
+
+    /// Publish that `count` entries of `category` were found.  This sets the
+    /// category's `DataUpdated` timestamp, which the UI lists, and emits a
+    /// `data_updated` event.  Any category may be used, not only the
+    /// built-in ones.
+    pub fn publish(&mut self, category: &'static str, count: usize) {
+        self.updated.mark(category);
+        events::emit(Event::DataUpdated { category, count });
+    }
+}
+
+pub trait PacketHandler: Send {
+    /// Short name of the kind of command handled, shown in the command
+    /// catalog and packet log decodes.
+    fn name(&self) -> &'static str;
+
+    /// Handle `command` if it's one this handler understands, publishing what
+    /// it found through `context`.  Returns whether it was handled.
+    fn handle(&mut self, command: &GameCommand, context: &mut HandlerContext<'_>) -> bool;
+
+    /// Add whatever the handler collected to a Genshin Optimizer export.
+    fn export_genshin_optimizer(&self, _good: &mut Good, _settings: &ExportSettings) {}
+
+    /// Decode `command` with its typed messages for the packet log, if it's
+    /// one this handler understands.
+    fn decode_json(&self, _command: &GameCommand) -> Option<serde_json::Value> {
+        None
+    }
+}
+
+pub struct ItemHandler;
+
+impl PacketHandler for ItemHandler {
+    fn name(&self) -> &'static str {
+        ITEMS
+    }
+
+    fn handle(&mut self, command: &GameCommand, context: &mut HandlerContext<'_>) -> bool {
+        let Some(items) = matches_item_packet(command) else {
+            return false;
+        };
+        tracing::info!("Found item packet with {} items", items.len());
+        context.player_data.process_items(&items);
+        context.publish(ITEMS, items.len());
+        true
+    }
+
+    fn decode_json(&self, command: &GameCommand) -> Option<serde_json::Value> {
+        matches_item_packet(command).map(|items| proto::messages_to_json(&items))
+    }
+}
+
+pub struct AvatarHandler;
+
+impl PacketHandler for AvatarHandler {
+    fn name(&self) -> &'static str {
+        AVATARS
+    }
+
+    fn handle(&mut self, command: &GameCommand, context: &mut HandlerContext<'_>) -> bool {
+        let Some(avatars) = matches_avatar_packet(command) else {
+            return false;
+        };
+        tracing::info!("Found avatar packet with {} avatars", avatars.len());
+        context.player_data.process_characters(&avatars);
+        context.publish(AVATARS, avatars.len());
+        true
+    }
+
+    fn decode_json(&self, command: &GameCommand) -> Option<serde_json::Value> {
+        matches_avatar_packet(command).map(|avatars| proto::messages_to_json(&avatars))
+    }
+}
+
+pub struct AchievementHandler;
+
+impl PacketHandler for AchievementHandler {
+    fn name(&self) -> &'static str {
+        ACHIEVEMENTS
+    }
+
+    fn handle(&mut self, command: &GameCommand, context: &mut HandlerContext<'_>) -> bool {
+        let Some(achievements) = matches_achievement_packet(command) else {
+            return false;
+        };
+        tracing::info!(
+            "Found achievement packet with {} achievements",
+            achievements.len()
+        );
+        context.player_data.process_achievements(&achievements);
+        context.publish(ACHIEVEMENTS, achievements.len());
+        true
+    }
+
+    fn decode_json(&self, command: &GameCommand) -> Option<serde_json::Value> {
+        matches_achievement_packet(command)
+            .map(|achievements| proto::messages_to_json(&achievements))
+    }
+}
+
+/// The handlers commands are offered to, in registration order.
+pub struct HandlerRegistry {
+    handlers: Vec<Box<dyn PacketHandler>>,
+}
+
+impl HandlerRegistry {
+    pub fn new() -> Self {
+        Self {
+            handlers: Vec::new(),
+        }
+    }
+
+    pub fn with_default_handlers() -> Self {
+        let mut registry = Self::new();
+        registry.register(ItemHandler);
+        registry.register(AvatarHandler);
+        registry.register(AchievementHandler);
+        registry
+    }
+
+    pub fn register(&mut self, handler: impl PacketHandler + 'static) {
+        self.handlers.push(Box::new(handler));
+    }
+
+    /// Offer `command` to every handler.  Returns the name of the first one
+    /// that handled it.
+    pub fn handle(
+        &mut self,
+        command: &GameCommand,
+        context: &mut HandlerContext<'_>,
+    ) -> Option<&'static str> {
+        let mut handled_by = None;
+        for handler in &mut self.handlers {
+            if handler.handle(command, context) {
+                handled_by = handled_by.or(Some(handler.name()));
+            }
+        }
+        handled_by
+    }
+
+    pub fn export_genshin_optimizer(&self, good: &mut Good, settings: &ExportSettings) {
+        for handler in &self.handlers {
+            handler.export_genshin_optimizer(good, settings);
+        }
+    }
+
+    /// Decode `command` with the typed messages of the handler called `name`.
+    pub fn decode_json(&self, name: &str, command: &GameCommand) -> Option<serde_json::Value> {
+        self.handlers
+            .iter()
+            .find(|handler| handler.name() == name)
+            .and_then(|handler| handler.decode_json(command))
+    }
+}
+
+impl Default for HandlerRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
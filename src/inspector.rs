@@ -0,0 +1,245 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use chrono::{DateTime, Local};
+use egui::{CollapsingHeader, RichText};
+use tokio::sync::watch;
+
+use crate::capture::Direction;
+use crate::packet_log::proto::{self, Field, FieldValue};
+
+/// Number of recent commands kept for the inspector.
+pub const RECENT_COMMANDS_LEN: usize = 256;
+
+/// Bytes per line of the hex view.
+const HEX_LINE_LEN: usize = 16;
+
+/// A decoded command as seen by the monitor.
+#[derive(Debug)]
+pub struct InspectedCommand {
+    /// Increases by one with every command, to tell them apart.
+    pub seq: u64,
+    pub timestamp: SystemTime,
+    pub command_id: u32,
+    pub direction: Direction,
+    /// The kind of message Irminsul handled it as, if any.
+    pub handled: Option<&'static str>,
+    pub payload: Vec<u8>,
+}
+
+/// The most recent commands, oldest first.
+pub type RecentCommands = VecDeque<Arc<InspectedCommand>>;
+
+pub fn push_recent_command(recent: &mut RecentCommands, command: InspectedCommand) {
+    if recent.len() >= RECENT_COMMANDS_LEN {
+        recent.pop_front();
+    }
+    recent.push_back(Arc::new(command));
+}
+
+struct Selection {
+    command: Arc<InspectedCommand>,
+    /// `None` if the payload isn't a valid protobuf message.
+    fields: Option<Vec<Field>>,
+}
+
+/// Window showing recent commands as a schemaless protobuf field tree and as
+/// hex.
+pub struct Inspector {
+    recent_commands_rx: watch::Receiver<RecentCommands>,
+    filter_text: String,
+    selection: Option<Selection>,
+}
+
+impl Inspector {
+    pub fn new(recent_commands_rx: watch::Receiver<RecentCommands>) -> Self {
+        Self {
+            recent_commands_rx,
+            filter_text: String::new(),
+            selection: None,
+        }
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context, open: &mut bool) {
+        egui::Window::new("Packet Inspector")
+            .open(open)
+            .default_size([700.0, 420.0])
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Command ids");
+                    ui.text_edit_singleline(&mut self.filter_text)
+                        .on_hover_text("Comma separated, empty to show all commands");
+                });
+                ui.separator();
+
+                ui.columns(2, |columns| {
+                    self.command_list_ui(&mut columns[0]);
+                    self.selection_ui(&mut columns[1]);
+                });
+            });
+    }
+
+    fn filter(&self) -> BTreeSet<u32> {
+        self.filter_text
+            .split(',')
+            .filter_map(|id| id.trim().parse().ok())
+            .collect()
+    }
+
+    fn command_list_ui(&mut self, ui: &mut egui::Ui) {
+        let filter = self.filter();
+        let recent = self.recent_commands_rx.borrow().clone();
+        let selected_seq = self
+            .selection
+            .as_ref()
+            .map(|selection| selection.command.seq);
+
+        egui::ScrollArea::vertical()
+            .id_salt("inspector_commands")
+            .stick_to_bottom(true)
+            .show(ui, |ui| {
+                for command in recent
+                    .iter()
+                    .filter(|command| filter.is_empty() || filter.contains(&command.command_id))
+                {
+                    let label = format!(
+                        "{} {:>5} {} {} bytes{}",
+                        DateTime::<Local>::from(command.timestamp).format("%H:%M:%S%.3f"),
+                        command.command_id,
+                        direction_arrow(command.direction),
+                        command.payload.len(),
+                        command
+                            .handled
+                            .map(|handled| format!(" ({handled})"))
+                            .unwrap_or_default(),
+                    );
+                    let selected = selected_seq == Some(command.seq);
+                    if ui
+                        .selectable_label(selected, RichText::new(label).monospace())
+                        .clicked()
+                    {
+                        self.selection = Some(Selection {
+                            fields: proto::decode_message(&command.payload),
+                            command: command.clone(),
+                        });
+                    }
+                }
+            });
+    }
+
+    fn selection_ui(&self, ui: &mut egui::Ui) {
+        let Some(selection) = &self.selection else {
+            ui.label("Select a command to inspect it.");
+            return;
+        };
+        let command = &selection.command;
+
+        ui.label(format!(
+            "Command {}, {}, {} bytes",
+            command.command_id,
+            command.direction,
+            command.payload.len()
+        ));
+        egui::ScrollArea::vertical()
+            .id_salt("inspector_selection")
+            .show(ui, |ui| {
+                CollapsingHeader::new("Fields")
+                    .id_salt(("inspector_fields", command.seq))
+                    .default_open(true)
+                    .show(ui, |ui| match &selection.fields {
+                        Some(fields) => fields_ui(ui, fields),
+                        None => {
+                            ui.label("Not a valid protobuf message.");
+                        }
+                    });
+                CollapsingHeader::new("Hex")
+                    .id_salt(("inspector_hex", command.seq))
+                    .show(ui, |ui| {
+                        ui.label(RichText::new(hex_dump(&command.payload)).monospace());
+                    });
+            });
+    }
+}
+
+fn direction_arrow(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Unknown => "?",
+        Direction::ClientToServer => "->",
+        Direction::ServerToClient => "<-",
+    }
+}
+
+fn fields_ui(ui: &mut egui::Ui, fields: &[Field]) {
+    for (i, field) in fields.iter().enumerate() {
+        let name = format!("{} ({})", field.number, field.value.wire_type());
+        match &field.value {
+            FieldValue::Message(fields) => {
+                CollapsingHeader::new(format!("{name}: message"))
+                    .id_salt((ui.id(), i))
+                    .show(ui, |ui| fields_ui(ui, fields));
+            }
+            FieldValue::TextOrMessage(text, fields) => {
+                CollapsingHeader::new(format!("{name}: {text:?} or message"))
+                    .id_salt((ui.id(), i))
+                    .show(ui, |ui| fields_ui(ui, fields));
+            }
+            value => {
+                ui.label(RichText::new(format!("{name}: {}", value_text(value))).monospace());
+            }
+        }
+    }
+}
+
+fn value_text(value: &FieldValue) -> String {
+    match value {
+        FieldValue::Varint(value) => {
+            let zigzag = ((value >> 1) as i64) ^ -((value & 1) as i64);
+            if *value > i64::MAX as u64 {
+                format!("{value} (signed {}, zigzag {zigzag})", *value as i64)
+            } else {
+                format!("{value} (zigzag {zigzag})")
+            }
+        }
+        FieldValue::Fixed64(value) => format!("{value} (double {})", f64::from_bits(*value)),
+        FieldValue::Fixed32(value) => format!("{value} (float {})", f32::from_bits(*value)),
+        FieldValue::String(text) => format!("{text:?}"),
+        FieldValue::Bytes(bytes) => match proto::decode_packed_varints(bytes) {
+            Some(values) if !values.is_empty() => {
+                format!("packed varints {values:?}")
+            }
+            _ => format!("bytes {}", proto::hex(bytes)),
+        },
+        FieldValue::Message(fields) => format!("message with {} fields", fields.len()),
+        FieldValue::TextOrMessage(text, fields) => {
+            format!("{text:?} or message with {} fields", fields.len())
+        }
+    }
+}
+
+fn hex_dump(data: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, line) in data.chunks(HEX_LINE_LEN).enumerate() {
+        let hex = line
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let text = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect::<String>();
+        dump.push_str(&format!(
+            "{:08x}  {hex:<width$}  {text}\n",
+            i * HEX_LINE_LEN,
+            width = HEX_LINE_LEN * 3 - 1
+        ));
+    }
+    dump
+}
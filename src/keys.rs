@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+// RecommendedWatcher is ReadDirectoryChangesWatcher on Windows, and INotifyWatcher on Linux
+use async_watcher::AsyncDebouncer;
+use async_watcher::notify::{RecommendedWatcher, RecursiveMode};
+use base64::prelude::*;
+use tokio::sync::mpsc;
+
+use crate::APP_ID;
+
+pub type Keys = HashMap<u16, Vec<u8>>;
+
+/// Location of the user-editable keys file.  Entries in this file are merged
+/// over the keys built into Irminsul.
+pub fn keys_path(override_path: Option<PathBuf>) -> Result<PathBuf> {
+    if let Some(path) = override_path {
+        // File watcher events carry absolute paths.
+        return Ok(std::path::absolute(path)?);
+    }
+
+    let mut path = eframe::storage_dir(APP_ID).context("Storage dir not found")?;
+    path.push("keys.json");
+    Ok(path)
+}
+
+fn parse_keys(data: &[u8]) -> Result<Keys> {
+    let keys: HashMap<u16, String> = serde_json::from_slice(data)?;
+
+    keys.iter()
+        .map(|(key, value)| -> Result<_, _> { Ok((*key, BASE64_STANDARD.decode(value)?)) })
+        .collect::<Result<Keys>>()
+}
+
+/// The keys built into Irminsul.
+pub fn builtin_keys() -> Result<Keys> {
+    parse_keys(include_bytes!("../keys/gi.json"))
+}
+
+/// Load the built-in keys merged with the contents of `path`, if it exists.
+pub fn load_keys(path: &Path) -> Result<Keys> {
+    let mut keys = builtin_keys()?;
+
+    if !path.exists() {
+        return Ok(keys);
+    }
+
+    let data = fs::read(path).with_context(|| format!("can't read keys file {path:?}"))?;
+    let overrides = parse_keys(&data).with_context(|| format!("can't parse keys file {path:?}"))?;
+    tracing::info!("Loaded {} keys from {path:?}", overrides.len());
+    keys.extend(overrides);
+
+    Ok(keys)
+}
+
+/// Watch `path` and send the merged keys every time it changes.
+pub async fn watch_keys(path: PathBuf, keys_tx: mpsc::UnboundedSender<Keys>) -> Result<()> {
+    // The keys file may not exist yet, so watch its directory instead.
+    let dir = path
+        .parent()
+        .context("keys file has no parent directory")?
+        .to_path_buf();
+    fs::create_dir_all(&dir).with_context(|| format!("can't create directory {dir:?}"))?;
+
+    let (mut debouncer, mut file_events) = AsyncDebouncer::<RecommendedWatcher>::new_with_channel(
+        Duration::from_secs(1),
+        Some(Duration::from_secs(1)),
+    )
+    .await?;
+    debouncer
+        .watcher()
+        .watch(&dir, RecursiveMode::NonRecursive)?;
+
+    while let Some(Ok(events)) = file_events.recv().await {
+        if !events.iter().any(|event| event.path == path) {
+            continue;
+        }
+
+        match load_keys(&path) {
+            Ok(keys) => {
+                if keys_tx.send(keys).is_err() {
+                    break;
+                }
+            }
+            Err(e) => tracing::error!("Error reloading keys: {e:#}"),
+        }
+    }
+
+    Ok(())
+}
@@ -1,8 +1,9 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")] // hide console window on Windows in release
 
+use std::collections::BTreeMap;
 use std::fmt::Display;
-use std::path::PathBuf;
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime};
 
 use anyhow::{Context, Result};
 use clap::Parser;
@@ -12,13 +13,19 @@ use tracing_appender::rolling::Rotation;
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::{EnvFilter, reload};
 
-use crate::player_data::ExportSettings;
+use crate::player_data::{ExportSettings, UnmappedIds};
 
 mod admin;
 mod app;
 mod capture;
+mod events;
+mod game_data;
 mod good;
+mod handlers;
+mod inspector;
+mod keys;
 mod monitor;
+mod packet_log;
 mod player_data;
 mod update;
 mod wish;
@@ -49,39 +56,150 @@ pub enum Message {
     UpdateAcknowledged,
     UpdateCanceled,
     DownloadAcknowledged,
+    DownloadCanceled,
     StartCapture,
     StopCapture,
     ExportGenshinOptimizer(ExportSettings, oneshot::Sender<Result<String>>),
+    /// Replay a packet log, replying with the number of commands replayed.
+    ReplayPacketLog(PathBuf, oneshot::Sender<Result<usize>>),
 }
 
-#[derive(Clone, Debug)]
-pub struct DataUpdated {
-    achievements_updated: Option<Instant>,
-    characters_updated: Option<Instant>,
-    items_updated: Option<Instant>,
-}
+/// When each kind of player data was last updated, keyed by the category
+/// handlers publish it under.
+#[derive(Clone, Debug, Default)]
+pub struct DataUpdated(BTreeMap<&'static str, Instant>);
 
 impl DataUpdated {
     pub fn new() -> Self {
-        Self {
-            achievements_updated: None,
-            characters_updated: None,
-            items_updated: None,
+        Self::default()
+    }
+
+    pub fn get(&self, category: &str) -> Option<Instant> {
+        self.0.get(category).copied()
+    }
+
+    pub fn mark(&mut self, category: &'static str) {
+        self.0.insert(category, Instant::now());
+    }
+
+    /// The updated categories, in name order.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, Instant)> + '_ {
+        self.0
+            .iter()
+            .map(|(category, updated)| (*category, *updated))
+    }
+}
+
+/// Counters describing how far captured packets make it through the
+/// sniffer.  Reset every time capture is started.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CaptureStats {
+    pub packets: u64,
+    pub packets_per_port: BTreeMap<u16, u64>,
+    pub handshakes: u64,
+    /// Packets which yielded no commands before any key matched the
+    /// session.  Includes acks and partial segments, so it hints at a key
+    /// mismatch rather than counting failed decryptions.
+    pub undecoded_packets: u64,
+    pub commands: u64,
+    /// Packets dropped because Irminsul couldn't keep up with the capture.
+    pub dropped_packets: u64,
+    /// Peak number of packets waiting in the backend and monitor queues.
+    pub backend_queue_high_water: usize,
+    pub monitor_queue_high_water: usize,
+}
+
+impl CaptureStats {
+    /// A human-readable explanation of what the counters say about the
+    /// capture.
+    pub fn diagnosis(&self, key_status: KeyStatus) -> &'static str {
+        if self.packets == 0 {
+            "No game traffic seen yet.  Make sure the game is running and connected."
+        } else if self.dropped_packets > 0 {
+            "Packets were dropped because Irminsul couldn't keep up.  Captured data may be \
+            incomplete."
+        } else if self.commands > 0 {
+            "Receiving and decoding game data."
+        } else if key_status == KeyStatus::NoMatch {
+            "Game traffic can't be decrypted.  The decryption keys may be out of date."
+        } else if self.handshakes == 0 {
+            "Game traffic seen but no login handshake.  Restart the game or log out and back in \
+            while capturing."
+        } else {
+            "Handshake seen, waiting for game data."
         }
     }
 }
 
-impl Default for DataUpdated {
-    fn default() -> Self {
-        Self::new()
+/// What's been seen of one command id since capture was started.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CommandSummary {
+    pub count: u64,
+    pub total_bytes: u64,
+    pub min_bytes: usize,
+    pub max_bytes: usize,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+    /// The kind of message Irminsul handled it as, if any.
+    pub handled: Option<&'static str>,
+}
+
+impl CommandSummary {
+    pub fn new(bytes: usize, seen: SystemTime, handled: Option<&'static str>) -> Self {
+        Self {
+            count: 1,
+            total_bytes: bytes as u64,
+            min_bytes: bytes,
+            max_bytes: bytes,
+            first_seen: seen,
+            last_seen: seen,
+            handled,
+        }
+    }
+
+    pub fn record(&mut self, bytes: usize, seen: SystemTime, handled: Option<&'static str>) {
+        self.count += 1;
+        self.total_bytes += bytes as u64;
+        self.min_bytes = self.min_bytes.min(bytes);
+        self.max_bytes = self.max_bytes.max(bytes);
+        self.first_seen = self.first_seen.min(seen);
+        self.last_seen = self.last_seen.max(seen);
+        self.handled = self.handled.or(handled);
     }
 }
 
+/// Every command id seen since capture was started, by id.
+pub type CommandCatalog = BTreeMap<u32, CommandSummary>;
+
+/// Whether the initial keys match the game session being captured.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum KeyStatus {
+    #[default]
+    Unknown,
+    Matched,
+    NoMatch,
+}
+
+/// Problems with the capture itself, as opposed to the game traffic.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum CaptureStatus {
+    #[default]
+    Ok,
+    /// The capture failed and is being restarted.
+    Restarting(String),
+    /// The capture failed and was stopped.
+    Failed(String),
+}
+
 #[derive(Clone, Debug)]
 pub struct AppState {
     state: State,
     capturing: bool,
+    capture_status: CaptureStatus,
     updated: DataUpdated,
+    key_status: KeyStatus,
+    capture_stats: CaptureStats,
+    unmapped_ids: UnmappedIds,
 }
 
 impl AppState {
@@ -89,7 +207,11 @@ impl AppState {
         AppState {
             state: State::Starting,
             capturing: false,
+            capture_status: CaptureStatus::Ok,
             updated: DataUpdated::new(),
+            key_status: KeyStatus::Unknown,
+            capture_stats: CaptureStats::default(),
+            unmapped_ids: UnmappedIds::default(),
         }
     }
 }
@@ -106,6 +228,93 @@ struct Args {
         default_value_t = capture::DEFAULT_CAPTURE_BACKEND_TYPE
     )]
     capture_backend: capture::BackendType,
+
+    /// URL of a gzipped game data file to use for runtime game data updates
+    /// instead of the default upstream sources.
+    #[arg(long = "game-data-url")]
+    game_data_url: Option<String>,
+
+    /// Load game data from a JSON or gzipped JSON file instead of the
+    /// embedded or downloaded copy.
+    #[arg(long = "game-data")]
+    game_data: Option<PathBuf>,
+
+    /// Keys file to merge over the built-in keys.  Defaults to `keys.json` in
+    /// the storage dir.  Changes are picked up without restarting.
+    #[arg(long = "keys")]
+    keys: Option<PathBuf>,
+
+    /// Capture only on the named device.  May be repeated.
+    #[arg(long = "capture-device")]
+    capture_devices: Vec<String>,
+
+    /// Capture only on devices whose name or description matches this regex.
+    /// May be repeated.
+    #[arg(long = "capture-include")]
+    capture_include: Vec<String>,
+
+    /// Never capture on devices whose name or description matches this regex.
+    /// May be repeated.
+    #[arg(long = "capture-exclude")]
+    capture_exclude: Vec<String>,
+
+    /// BPF expression to use instead of the default game port filter.
+    #[arg(long = "capture-filter")]
+    capture_filter: Option<String>,
+
+    /// Game server ports as a comma separated list of ports and ranges, e.g.
+    /// `22101-22102,23301`.
+    #[arg(long = "ports")]
+    ports: Option<capture::PortRanges>,
+
+    /// Lock on to the server port of the first handshake seen instead of only
+    /// watching the configured ports.  `--auto-detect-port=false` turns it
+    /// off even when enabled in the settings.
+    #[arg(
+        long = "auto-detect-port",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    auto_detect_port: Option<bool>,
+
+    /// Address the `mirror` capture backend listens on for forwarded
+    /// packets, e.g. `udp://0.0.0.0:9011` or `tcp://0.0.0.0:9011`.  Defaults
+    /// to `udp://127.0.0.1:9011`; forwarded packets aren't authenticated, so
+    /// only listen on other interfaces on a trusted network.
+    #[arg(long = "mirror-listen")]
+    mirror_listen: Option<capture::MirrorAddress>,
+
+    /// Read a pcap stream from stdin instead of capturing, e.g.
+    /// `tcpdump -w - | irminsul --stdin`.  Implies `--no-admin`.
+    #[arg(long = "stdin", default_value_t = false)]
+    stdin: bool,
+
+    /// Replay a packet log recorded with "Log raw packets" once Irminsul has
+    /// started: a session's `.pktlog` file, or a folder of `.bin` files from
+    /// older versions.  Implies `--no-admin`.
+    #[arg(long = "replay")]
+    replay: Option<PathBuf>,
+
+    /// Write a Genshin Optimizer export of the `--replay` packet log to this
+    /// file and exit, without starting the UI.  Uses the cached or embedded
+    /// game data unless `--game-data` is given, and the default export
+    /// settings.
+    #[arg(long = "export", requires = "replay")]
+    export: Option<PathBuf>,
+
+    /// Write machine-readable events to stdout: capture state changes, data
+    /// updates, wish URL discovery and errors.  On Windows, run from an
+    /// elevated shell or with `--no-admin`, as Irminsul won't re-launch
+    /// itself as administrator.
+    #[arg(long = "events", value_enum)]
+    events: Option<events::EventFormat>,
+
+    /// Run as the privileged capture helper, connecting to the GUI on this
+    /// socket.  Started by Irminsul itself through pkexec.
+    #[cfg(target_os = "linux")]
+    #[arg(long = "capture-helper", hide = true)]
+    capture_helper: Option<PathBuf>,
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize, Default)]
@@ -157,16 +366,68 @@ impl ReloadHandle {
 }
 
 fn main() -> eframe::Result {
+    // Under sudo, keep settings, logs and exports with the user who ran it.
+    // This changes the environment, so it has to happen before tracing and
+    // the tokio runtime start any threads.
+    #[cfg(unix)]
+    admin::use_sudo_user_home();
     let (_guard, reload_handle) = tracing_init().unwrap();
+    #[cfg(unix)]
+    admin::chown_storage_dir_to_sudo_user();
 
     let args = Args::parse();
+    if let Some(format) = args.events {
+        events::enable(format);
+    }
 
-    if !args.no_admin {
-        #[cfg(any(windows, unix))]
+    #[cfg(target_os = "linux")]
+    if let Some(socket_path) = &args.capture_helper {
+        if let Err(e) = capture::helper::run(socket_path) {
+            tracing::error!("Capture helper failed: {e:#}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    if let (Some(replay), Some(export)) = (&args.replay, &args.export) {
+        if let Err(e) = export_replay(replay, export, args.game_data.as_deref()) {
+            tracing::error!("Replay export failed: {e:#}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    // Whatever writes to stdin does the privileged capturing.
+    if !args.no_admin && !args.stdin && args.replay.is_none() {
+        // Elevating re-launches Irminsul, and the events of the new process
+        // wouldn't reach whoever reads ours.
+        #[cfg(windows)]
+        if args.events.is_some() && !admin::is_admin() {
+            tracing::error!(
+                "Not elevating to admin privileges with --events, run from an elevated shell \
+                 or pass --no-admin"
+            );
+        } else {
+            admin::ensure_admin();
+        }
+        #[cfg(unix)]
         admin::ensure_admin();
     }
 
-    let capture_backend = args.capture_backend;
+    let capture_backend = if args.stdin {
+        capture::BackendType::Stdin
+    } else {
+        args.capture_backend
+    };
+    let capture_overrides = capture::CaptureOverrides {
+        devices: args.capture_devices,
+        include: args.capture_include,
+        exclude: args.capture_exclude,
+        filter: args.capture_filter,
+        ports: args.ports,
+        auto_detect_port: args.auto_detect_port,
+        mirror: args.mirror_listen,
+    };
 
     let background_image_size = [1600., 1000.];
 
@@ -190,16 +451,58 @@ fn main() -> eframe::Result {
             Ok(Box::new(app::IrminsulApp::new(
                 cc,
                 reload_handle,
-                capture_backend,
+                app::LaunchOptions {
+                    capture_backend,
+                    game_data_url: args.game_data_url,
+                    game_data_path: args.game_data,
+                    keys_path: args.keys,
+                    capture_overrides,
+                    replay: args.replay,
+                },
             )))
         }),
     )
 }
 
+/// Replay the packet log at `replay` and write a Genshin Optimizer export of
+/// the player data it contains to `export`.
+fn export_replay(replay: &Path, export: &Path, game_data: Option<&Path>) -> Result<()> {
+    let game_data = match game_data {
+        Some(path) => game_data::load_file(path)?,
+        None => match game_data::load_cached() {
+            Ok(Some(db)) => db,
+            Ok(None) => game_data::load_embedded()?,
+            Err(e) => {
+                tracing::warn!("Unable to load cached game data: {e:#}");
+                game_data::load_embedded()?
+            }
+        },
+    };
+    let mut player_data = player_data::PlayerData::new(game_data);
+    let mut handlers = handlers::HandlerRegistry::with_default_handlers();
+    let mut updated = DataUpdated::new();
+
+    let records = packet_log::read_records(replay)?;
+    tracing::info!("Replaying {} commands from {replay:?}", records.len());
+    for record in records {
+        let command = record.into_command()?;
+        let mut context = handlers::HandlerContext::new(&mut player_data, &mut updated);
+        handlers.handle(&command, &mut context);
+    }
+
+    let settings = ExportSettings::default();
+    let mut good = player_data.export_genshin_optimizer(&settings);
+    handlers.export_genshin_optimizer(&mut good, &settings);
+    let json = serde_json::to_string(&good)?;
+    std::fs::write(export, json).with_context(|| format!("can't write file {export:?}"))?;
+    tracing::info!("Wrote Genshin Optimizer export to {export:?}");
+    Ok(())
+}
+
 fn log_dir() -> Result<PathBuf> {
     let mut dir = eframe::storage_dir(APP_ID).context("Storage dir not found")?;
     dir.push("log");
-    println!("Log folder: {}", dir.display());
+    eprintln!("Log folder: {}", dir.display());
     Ok(dir)
 }
 
@@ -225,6 +528,7 @@ fn tracing_init() -> Result<(tracing_appender::non_blocking::WorkerGuard, Reload
     tracing_subscriber::registry()
         .with(filter)
         .with(writer)
+        .with(events::ErrorEventLayer)
         .init();
     tracing::info!("Tracing initialized and logging to file.");
 
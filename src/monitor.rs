@@ -1,23 +1,35 @@
-use std::collections::HashMap;
-use std::fs;
-use std::io::{BufWriter, Write};
-use std::time::Instant;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use anime_game_data::AnimeGameData;
-use anyhow::{Context, Result, anyhow};
-use auto_artifactarium::{
-    GameCommand, GamePacket, GameSniffer, matches_achievement_packet, matches_avatar_packet,
-    matches_item_packet,
-};
-use base64::prelude::*;
-use chrono::prelude::*;
-use flate2::read::GzDecoder;
-use tokio::sync::{mpsc, watch};
+use anyhow::Result;
+use auto_artifactarium::{GameCommand, GamePacket, GameSniffer};
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio_util::sync::CancellationToken;
 
-use crate::capture::{BackendType, create_capture};
-use crate::player_data::PlayerData;
-use crate::{APP_ID, AppState, DataUpdated, Message, State};
+use crate::capture::dedup::Deduplicator;
+use crate::capture::parse::{parse_ethernet_udp, rewrite_udp_ports};
+use crate::capture::queue::{self, MONITOR_QUEUE_LEN, PacketSender, QueueStats};
+use crate::capture::reassembly::Reassembler;
+use crate::capture::{
+    BackendType, CaptureConfig, CaptureError, CapturedPacket, Direction, PORT_RANGE, PortRanges,
+    create_capture,
+};
+use crate::events::{self, Event};
+use crate::game_data::{self, GameDataSource};
+use crate::handlers::{HandlerContext, HandlerRegistry};
+use crate::inspector::{self, InspectedCommand, RecentCommands};
+use crate::keys::{self, Keys};
+use crate::packet_log::{self, LogRecord, PacketLogSettings, PacketLogWriter};
+use crate::player_data::{PlayerData, UnmappedIds};
+use crate::{
+    AppState, CaptureStats, CaptureStatus, CommandCatalog, CommandSummary, ConfirmationType,
+    DataUpdated, KeyStatus, Message, State,
+};
+
+/// Where the result of a packet log replay is sent.
+type ReplayReply = oneshot::Sender<Result<usize>>;
 
 struct AppStateManager {
     app_state: AppState,
@@ -38,63 +50,259 @@ impl AppStateManager {
     }
 
     pub fn update_capturing_state(&mut self, capturing: bool) {
+        events::emit(if capturing {
+            Event::CaptureStarted
+        } else {
+            Event::CaptureStopped
+        });
         self.app_state.capturing = capturing;
         let _ = self.state_tx.send(self.app_state.clone());
     }
 
+    pub fn update_capture_status(&mut self, capture_status: CaptureStatus) {
+        if self.app_state.capture_status == capture_status {
+            return;
+        }
+        match &capture_status {
+            CaptureStatus::Ok => (),
+            CaptureStatus::Restarting(reason) => events::emit(Event::CaptureRestarting { reason }),
+            CaptureStatus::Failed(reason) => events::emit(Event::CaptureFailed { reason }),
+        }
+        self.app_state.capture_status = capture_status;
+        let _ = self.state_tx.send(self.app_state.clone());
+    }
+
     pub fn update_timestamps(&mut self, updated: DataUpdated) {
         self.app_state.updated = updated;
         let _ = self.state_tx.send(self.app_state.clone());
     }
+
+    pub fn update_key_status(&mut self, key_status: KeyStatus) {
+        self.app_state.key_status = key_status;
+        let _ = self.state_tx.send(self.app_state.clone());
+    }
+
+    pub fn update_capture_stats(&mut self, capture_stats: CaptureStats) {
+        self.app_state.capture_stats = capture_stats;
+        let _ = self.state_tx.send(self.app_state.clone());
+    }
+
+    pub fn update_unmapped_ids(&mut self, unmapped_ids: UnmappedIds) {
+        self.app_state.unmapped_ids = unmapped_ids;
+        let _ = self.state_tx.send(self.app_state.clone());
+    }
 }
 
 pub struct Monitor {
     app_state: AppStateManager,
     ui_message_rx: mpsc::UnboundedReceiver<Message>,
-    log_packet_rx: watch::Receiver<bool>,
+    /// UI messages received while waiting for a download confirmation,
+    /// handled once `run` starts.
+    queued_ui_messages: Vec<Message>,
+    log_packet_rx: watch::Receiver<PacketLogSettings>,
+    capture_config_rx: watch::Receiver<CaptureConfig>,
     player_data: PlayerData,
+    handlers: HandlerRegistry,
     sniffer: GameSniffer,
     capture_cancel_token: Option<CancellationToken>,
-    packet_tx: mpsc::UnboundedSender<Vec<u8>>,
-    packet_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    packet_tx: PacketSender<CapturedPacket>,
+    packet_rx: mpsc::Receiver<CapturedPacket>,
+    backend_queue_stats: Arc<QueueStats>,
+    monitor_queue_stats: Arc<QueueStats>,
+    capture_event_tx: mpsc::UnboundedSender<(u64, CaptureEvent)>,
+    capture_event_rx: mpsc::UnboundedReceiver<(u64, CaptureEvent)>,
+    /// Packet logs read for replay off the monitor task.
+    replay_tx: mpsc::UnboundedSender<(Result<Vec<LogRecord>>, ReplayReply)>,
+    replay_rx: mpsc::UnboundedReceiver<(Result<Vec<LogRecord>>, ReplayReply)>,
+    /// Identifies the current capture in its events.  Incremented every time
+    /// capture is started.
+    capture_id: u64,
+    keys_rx: mpsc::UnboundedReceiver<Keys>,
     capture_backend: BackendType,
+
+    /// Archive of the current capture session's commands, opened when the
+    /// first command is logged.
+    packet_log: Option<PacketLogWriter>,
+
+    /// Game server ports of the current capture.  Grows when a port is
+    /// auto-detected.
+    ports: PortRanges,
+    auto_detect_port: bool,
+
+    /// Packets containing KCP segments since the last handshake or decoded
+    /// command.  Used to detect sessions whose key we don't have.
+    undecoded_packets: usize,
+
+    /// Counters are published to the UI periodically rather than per packet.
+    capture_stats: CaptureStats,
+    diagnosis: &'static str,
+
+    /// Every command id seen since capture was started, for the command
+    /// catalog.
+    command_catalog_tx: watch::Sender<CommandCatalog>,
+
+    /// Recent commands for the packet inspector, only recorded while it's
+    /// open.
+    inspector_open_rx: watch::Receiver<bool>,
+    recent_commands_tx: watch::Sender<RecentCommands>,
+    next_command_seq: u64,
+}
+
+/// Number of undecodable packets after which we assume that none of our keys
+/// match the current session.
+const KEY_MISMATCH_THRESHOLD: usize = 200;
+
+const CAPTURE_STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Delay before the first capture restart.  Doubles with every failed restart
+/// up to `CAPTURE_RESTART_MAX_DELAY`.
+const CAPTURE_RESTART_DELAY: Duration = Duration::from_secs(1);
+const CAPTURE_RESTART_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Restarts in a row without receiving a packet before giving up.
+const CAPTURE_MAX_RESTARTS: u32 = 5;
+
+/// Sent by the capture task when its status changes.
+enum CaptureEvent {
+    Running,
+    Restarting(String),
+    Failed(String),
+}
+
+/// Sends the events of one capture tagged with its id, so that events still
+/// arriving from a stopped capture can be told apart from the current one's.
+struct CaptureEventSender {
+    capture_id: u64,
+    tx: mpsc::UnboundedSender<(u64, CaptureEvent)>,
+}
+
+impl CaptureEventSender {
+    fn send(&self, event: CaptureEvent) {
+        let _ = self.tx.send((self.capture_id, event));
+    }
+}
+
+/// Why a capture run ended.
+enum CaptureEnd {
+    Cancelled,
+    Restart(String),
+    Fatal(String),
+}
+
+/// The channels between the monitor and the UI.
+pub struct MonitorChannels {
+    pub state_tx: watch::Sender<AppState>,
+    pub ui_message_rx: mpsc::UnboundedReceiver<Message>,
+    pub log_packet_rx: watch::Receiver<PacketLogSettings>,
+    pub capture_config_rx: watch::Receiver<CaptureConfig>,
+    pub command_catalog_tx: watch::Sender<CommandCatalog>,
+    /// Whether the packet inspector is open, so recent commands are only
+    /// recorded while someone is looking.
+    pub inspector_open_rx: watch::Receiver<bool>,
+    pub recent_commands_tx: watch::Sender<RecentCommands>,
 }
 
 impl Monitor {
     pub async fn new(
-        state_tx: watch::Sender<AppState>,
-        mut ui_message_rx: mpsc::UnboundedReceiver<Message>,
-        log_packet_rx: watch::Receiver<bool>,
+        channels: MonitorChannels,
         capture_backend: BackendType,
+        game_data_source: GameDataSource,
+        keys_path: Option<PathBuf>,
     ) -> Result<Self> {
+        let MonitorChannels {
+            state_tx,
+            mut ui_message_rx,
+            log_packet_rx,
+            capture_config_rx,
+            command_catalog_tx,
+            inspector_open_rx,
+            recent_commands_tx,
+        } = channels;
         let mut app_state = AppStateManager::new(state_tx.borrow().clone(), state_tx.clone());
-        let game_data = get_database(&mut app_state, &mut ui_message_rx).await?;
+        let mut queued_ui_messages = Vec::new();
+        let game_data = get_database(
+            &mut app_state,
+            &mut ui_message_rx,
+            &mut queued_ui_messages,
+            &game_data_source,
+        )
+        .await?;
         let player_data = PlayerData::new(game_data);
-        let keys = load_keys()?;
+        let keys_path = keys::keys_path(keys_path)?;
+        let keys = match keys::load_keys(&keys_path) {
+            Ok(keys) => keys,
+            Err(e) => {
+                tracing::error!("Error loading keys, using built-in keys: {e:#}");
+                keys::builtin_keys()?
+            }
+        };
         let sniffer = GameSniffer::new().set_initial_keys(keys);
-        let (packet_tx, packet_rx) = mpsc::unbounded_channel();
+        let backend_queue_stats = Arc::new(QueueStats::default());
+        let monitor_queue_stats = Arc::new(QueueStats::default());
+        let (packet_tx, packet_rx) =
+            queue::packet_queue(MONITOR_QUEUE_LEN, monitor_queue_stats.clone());
+        let (capture_event_tx, capture_event_rx) = mpsc::unbounded_channel();
+        let (replay_tx, replay_rx) = mpsc::unbounded_channel();
+
+        let (keys_tx, keys_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            if let Err(e) = keys::watch_keys(keys_path, keys_tx).await {
+                tracing::error!("Error watching keys file: {e}");
+            }
+        });
 
         Ok(Self {
             app_state,
             player_data,
+            handlers: HandlerRegistry::with_default_handlers(),
             ui_message_rx,
+            queued_ui_messages,
             log_packet_rx,
+            capture_config_rx,
             sniffer,
             capture_cancel_token: None,
             packet_tx,
             packet_rx,
+            backend_queue_stats,
+            monitor_queue_stats,
+            capture_event_tx,
+            capture_event_rx,
+            replay_tx,
+            replay_rx,
+            capture_id: 0,
+            keys_rx,
             capture_backend,
+            packet_log: None,
+            ports: PortRanges::default(),
+            auto_detect_port: false,
+            undecoded_packets: 0,
+            capture_stats: CaptureStats::default(),
+            diagnosis: "",
+            command_catalog_tx,
+            inspector_open_rx,
+            recent_commands_tx,
+            next_command_seq: 0,
         })
     }
 
     pub async fn run(mut self) {
         self.app_state.update_app_state(State::Main);
+        for msg in std::mem::take(&mut self.queued_ui_messages) {
+            self.handle_ui_msg(msg);
+        }
 
+        let mut stats_interval = tokio::time::interval(CAPTURE_STATS_INTERVAL);
         loop {
             #[rustfmt::skip]
                 tokio::select! {
                     Some(packet) = self.packet_rx.recv() => self.handle_packet(packet),
                     Some(msg) = self.ui_message_rx.recv() => self.handle_ui_msg(msg),
+                    Some((id, event)) = self.capture_event_rx.recv() => self.handle_capture_event(id, event),
+                    Some((records, reply_tx)) = self.replay_rx.recv() => {
+                        let _ = reply_tx.send(records.and_then(|records| self.replay_records(records)));
+                    }
+                    Some(keys) = self.keys_rx.recv() => self.handle_keys_update(keys),
+                    _ = stats_interval.tick() => self.publish_capture_stats(),
                 }
         }
     }
@@ -106,14 +314,32 @@ impl Monitor {
                     tracing::warn!("Capture start request with an existing cancel token");
                 }
 
+                let config = self.capture_config_rx.borrow_and_update().clone();
+                self.ports = config.ports();
+                self.auto_detect_port = config.auto_detect_port;
+
                 // Spawn capture task.
                 let cancel_token = CancellationToken::new();
+                self.capture_id += 1;
                 tokio::spawn(capture_task(
                     cancel_token.clone(),
                     self.packet_tx.clone(),
+                    CaptureEventSender {
+                        capture_id: self.capture_id,
+                        tx: self.capture_event_tx.clone(),
+                    },
+                    self.backend_queue_stats.clone(),
                     self.capture_backend,
+                    config,
                 ));
                 self.capture_cancel_token = Some(cancel_token);
+                self.packet_log = None;
+                self.capture_stats = CaptureStats::default();
+                self.backend_queue_stats.reset();
+                self.monitor_queue_stats.reset();
+                self.diagnosis = "";
+                self.command_catalog_tx.send_modify(CommandCatalog::clear);
+                self.app_state.update_capture_status(CaptureStatus::Ok);
                 self.app_state.update_capturing_state(true);
             }
             Message::StopCapture => {
@@ -122,126 +348,566 @@ impl Monitor {
                     return;
                 };
                 cancel_token.cancel();
+                self.packet_log = None;
+                self.app_state.update_capture_status(CaptureStatus::Ok);
                 self.app_state.update_capturing_state(false);
             }
             Message::ExportGenshinOptimizer(settings, reply_tx) => {
-                let _ = reply_tx.send(self.player_data.export_genshin_optimizer(&settings));
+                let mut good = self.player_data.export_genshin_optimizer(&settings);
+                self.handlers.export_genshin_optimizer(&mut good, &settings);
+                let json = serde_json::to_string(&good).map_err(anyhow::Error::from);
+                if let Ok(json) = &json {
+                    tracing::trace!("{json}");
+                }
+                let _ = reply_tx.send(json);
+            }
+            Message::ReplayPacketLog(path, reply_tx) => {
+                // Archives can be large, so keep reading them off the monitor task.
+                let replay_tx = self.replay_tx.clone();
+                tokio::spawn(async move {
+                    let records =
+                        tokio::task::spawn_blocking(move || packet_log::read_records(&path))
+                            .await
+                            .unwrap_or_else(|e| Err(e.into()));
+                    let _ = replay_tx.send((records, reply_tx));
+                });
             }
             _ => (),
         }
     }
 
-    fn handle_packet(&mut self, packet: Vec<u8>) {
-        let Some(GamePacket::Commands(commands)) = self.sniffer.receive_packet(packet) else {
+    fn handle_capture_event(&mut self, capture_id: u64, event: CaptureEvent) {
+        // Events may still arrive from a capture that was just stopped, even
+        // after a new one has started.
+        if capture_id != self.capture_id || self.capture_cancel_token.is_none() {
+            return;
+        }
+
+        match event {
+            CaptureEvent::Running => self.app_state.update_capture_status(CaptureStatus::Ok),
+            CaptureEvent::Restarting(error) => {
+                self.app_state
+                    .update_capture_status(CaptureStatus::Restarting(error));
+            }
+            CaptureEvent::Failed(error) => {
+                tracing::error!("Capture stopped: {error}");
+                self.capture_cancel_token = None;
+                self.app_state
+                    .update_capture_status(CaptureStatus::Failed(error));
+                self.app_state.update_capturing_state(false);
+            }
+        }
+    }
+
+    fn handle_keys_update(&mut self, keys: Keys) {
+        tracing::info!("Reloaded {} keys", keys.len());
+        // Replacing the keys in place keeps the current session's state.
+        let sniffer = std::mem::replace(&mut self.sniffer, GameSniffer::new());
+        self.sniffer = sniffer.set_initial_keys(keys);
+        self.undecoded_packets = 0;
+        self.app_state.update_key_status(KeyStatus::Unknown);
+    }
+
+    fn publish_capture_stats(&mut self) {
+        // The capture task waits for space in the monitor queue, which pushes
+        // back into the backend queue, so only the latter drops packets.
+        self.capture_stats.dropped_packets = self.backend_queue_stats.dropped();
+        self.capture_stats.backend_queue_high_water = self.backend_queue_stats.high_water();
+        self.capture_stats.monitor_queue_high_water = self.monitor_queue_stats.high_water();
+
+        if !self.app_state.app_state.capturing
+            || self.capture_stats == self.app_state.app_state.capture_stats
+        {
             return;
+        }
+
+        let diagnosis = self
+            .capture_stats
+            .diagnosis(self.app_state.app_state.key_status);
+        if diagnosis != self.diagnosis {
+            let stats = &self.capture_stats;
+            tracing::info!(
+                "Capture: {} packets {:?}, {} handshakes, {} undecoded, {} commands, {} dropped: {diagnosis}",
+                stats.packets,
+                stats.packets_per_port,
+                stats.handshakes,
+                stats.undecoded_packets,
+                stats.commands,
+                stats.dropped_packets,
+            );
+            self.diagnosis = diagnosis;
+        }
+
+        self.app_state
+            .update_capture_stats(self.capture_stats.clone());
+    }
+
+    fn handle_packet(&mut self, mut packet: CapturedPacket) {
+        self.capture_stats.packets += 1;
+        if let Some(datagram) = parse_ethernet_udp(&packet.data) {
+            let on_game_port = self.ports.contains(datagram.src.port())
+                || self.ports.contains(datagram.dst.port());
+            if !on_game_port {
+                if !(self.auto_detect_port && datagram.is_handshake_request()) {
+                    return;
+                }
+                let port = datagram.dst.port();
+                tracing::info!("Detected game server port {port}");
+                self.ports.insert(port);
+            }
+
+            *self
+                .capture_stats
+                .packets_per_port
+                .entry(datagram.server_port(&self.ports))
+                .or_default() += 1;
+        }
+
+        packet.classify(&self.ports);
+        tracing::trace!(
+            "{} packet {:?} -> {:?} on {}, {} bytes",
+            packet.direction,
+            packet.src,
+            packet.dst,
+            packet.interface.as_deref().unwrap_or("unknown interface"),
+            packet.data.len()
+        );
+
+        // The sniffer recognizes server traffic by the default ports, so map
+        // any other configured port onto them.
+        let ports = &self.ports;
+        let default_ports = PORT_RANGE.0..=PORT_RANGE.1;
+        rewrite_udp_ports(&mut packet.data, |port| {
+            if ports.contains(port) && !default_ports.contains(&port) {
+                PORT_RANGE.0
+            } else {
+                port
+            }
+        });
+
+        let (timestamp, direction) = (packet.timestamp, packet.direction);
+        let commands = match self.sniffer.receive_packet(packet.data) {
+            Some(GamePacket::Commands(commands)) => commands,
+            Some(_) => {
+                self.capture_stats.handshakes += 1;
+                // Connection state changed, a new handshake will select a new key.
+                self.undecoded_packets = 0;
+                if self.app_state.app_state.key_status == KeyStatus::Matched {
+                    self.app_state.update_key_status(KeyStatus::Unknown);
+                }
+                return;
+            }
+            None => return,
         };
+        self.capture_stats.commands += commands.len() as u64;
+        self.update_key_status(!commands.is_empty());
 
-        let log_packets = *self.log_packet_rx.borrow_and_update();
+        let log_settings = *self.log_packet_rx.borrow_and_update();
+        if !log_settings.enabled {
+            self.packet_log = None;
+        }
 
         let mut updated = self.app_state.app_state.updated.clone();
         let mut has_new_data = false;
 
         for command in commands {
-            let _span = tracing::info_span!("packet id {}", command.command_id);
-            if log_packets {
-                if let Err(e) = log_command(&command) {
+            let message = self.handle_command(&command, &mut updated);
+            has_new_data |= message.is_some();
+            self.record_command(&command, timestamp, direction, message);
+
+            if log_settings.enabled {
+                let message = message.filter(|_| log_settings.decode);
+                if let Err(e) = self.log_command(&command, timestamp, direction, message) {
                     tracing::info!("error logging command {e}");
+                    // The archive may end in a partial record, so start a new one.
+                    self.packet_log = None;
                 }
             }
+        }
+        if log_settings.enabled
+            && let Some(packet_log) = &mut self.packet_log
+            && let Err(e) = packet_log.flush()
+        {
+            tracing::info!("error flushing packet log {e}");
+            self.packet_log = None;
+        }
 
-            if let Some(items) = matches_item_packet(&command) {
-                tracing::info!("Found item packet with {} items", items.len());
-                self.player_data.process_items(&items);
-                updated.items_updated = Some(Instant::now());
-                has_new_data = true;
-            } else if let Some(avatars) = matches_avatar_packet(&command) {
-                tracing::info!("Found avatar packet with {} avatars", avatars.len());
-                self.player_data.process_characters(&avatars);
-                updated.characters_updated = Some(Instant::now());
-                has_new_data = true;
-            } else if let Some(achievements) = matches_achievement_packet(&command) {
-                tracing::info!(
-                    "Found achievement packet with {} achievements",
-                    achievements.len()
-                );
-                self.player_data.process_achievements(&achievements);
-                updated.achievements_updated = Some(Instant::now());
-                has_new_data = true;
-            }
+        if has_new_data {
+            self.app_state.update_timestamps(updated);
+            self.check_unmapped_ids();
+        }
+    }
+
+    /// Feed a decoded command to the player data.  Returns which kind of
+    /// message it was if Irminsul understood it.
+    fn handle_command(
+        &mut self,
+        command: &GameCommand,
+        updated: &mut DataUpdated,
+    ) -> Option<&'static str> {
+        let _span = tracing::info_span!("packet id {}", command.command_id);
+
+        let mut context = HandlerContext::new(&mut self.player_data, updated);
+        self.handlers.handle(command, &mut context)
+    }
+
+    fn record_command(
+        &mut self,
+        command: &GameCommand,
+        timestamp: SystemTime,
+        direction: Direction,
+        handled: Option<&'static str>,
+    ) {
+        let bytes = command.proto_data.len();
+        self.command_catalog_tx.send_modify(|catalog| {
+            catalog
+                .entry(command.command_id.into())
+                .and_modify(|summary| summary.record(bytes, timestamp, handled))
+                .or_insert_with(|| CommandSummary::new(bytes, timestamp, handled));
+        });
+
+        if !*self.inspector_open_rx.borrow() {
+            return;
+        }
+        let inspected = InspectedCommand {
+            seq: self.next_command_seq,
+            timestamp,
+            command_id: command.command_id.into(),
+            direction,
+            handled,
+            payload: command.proto_data.clone(),
+        };
+        self.next_command_seq += 1;
+        self.recent_commands_tx
+            .send_modify(|recent| inspector::push_recent_command(recent, inspected));
+    }
+
+    /// Feed the commands of a packet log back through `handle_command`, as if
+    /// they had just been captured.  Returns the number of commands replayed.
+    fn replay_records(&mut self, records: Vec<LogRecord>) -> Result<usize> {
+        tracing::info!("Replaying {} commands", records.len());
+
+        let mut updated = self.app_state.app_state.updated.clone();
+        let mut has_new_data = false;
+        let count = records.len();
+        for record in records {
+            let (timestamp, direction) = (record.timestamp, record.direction);
+            let command = record.into_command()?;
+            let message = self.handle_command(&command, &mut updated);
+            has_new_data |= message.is_some();
+            self.record_command(&command, timestamp, direction, message);
         }
 
         if has_new_data {
             self.app_state.update_timestamps(updated);
+            self.check_unmapped_ids();
         }
+        Ok(count)
+    }
+
+    fn log_command(
+        &mut self,
+        command: &GameCommand,
+        timestamp: SystemTime,
+        direction: Direction,
+        message: Option<&str>,
+    ) -> Result<()> {
+        if self.packet_log.is_none() {
+            self.packet_log = Some(PacketLogWriter::create()?);
+        }
+        let Some(packet_log) = &mut self.packet_log else {
+            return Ok(());
+        };
+
+        let record = LogRecord {
+            timestamp,
+            command_id: command.command_id.into(),
+            direction,
+            payload: command.proto_data.clone(),
+        };
+        let fields = message.and_then(|message| self.handlers.decode_json(message, command));
+        packet_log.write(&record, message, fields)
+    }
+
+    fn update_key_status(&mut self, decoded: bool) {
+        let key_status = self.app_state.app_state.key_status;
+        if decoded {
+            self.undecoded_packets = 0;
+            if key_status != KeyStatus::Matched {
+                self.app_state.update_key_status(KeyStatus::Matched);
+            }
+            return;
+        }
+
+        // Once a session has decoded commands, packets without any (acks,
+        // partial segments) are expected.
+        if key_status != KeyStatus::Unknown {
+            return;
+        }
+
+        self.undecoded_packets += 1;
+        self.capture_stats.undecoded_packets += 1;
+        if self.undecoded_packets >= KEY_MISMATCH_THRESHOLD {
+            tracing::warn!(
+                "No commands decoded from the last {} packets, no key matches the session",
+                self.undecoded_packets
+            );
+            self.app_state.update_key_status(KeyStatus::NoMatch);
+        }
+    }
+
+    fn check_unmapped_ids(&mut self) {
+        let unmapped_ids = self.player_data.unmapped_ids();
+        if unmapped_ids == self.app_state.app_state.unmapped_ids {
+            return;
+        }
+
+        unmapped_ids.warn_new(&self.app_state.app_state.unmapped_ids);
+        if !unmapped_ids.is_empty() {
+            tracing::warn!(
+                "{} game ids are missing from game data and will not be exported",
+                unmapped_ids.len()
+            );
+        }
+        self.app_state.update_unmapped_ids(unmapped_ids);
     }
 }
 
 async fn get_database(
     app_state: &mut AppStateManager,
-    _ui_message_rx: &mut mpsc::UnboundedReceiver<Message>,
+    ui_message_rx: &mut mpsc::UnboundedReceiver<Message>,
+    queued_ui_messages: &mut Vec<Message>,
+    source: &GameDataSource,
 ) -> Result<AnimeGameData> {
     app_state.update_app_state(State::CheckingForData);
 
-    static DATABASE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/game_data.gz"));
-    let reader = GzDecoder::new(DATABASE);
-    let db = anime_game_data::AnimeGameData::new_from_reader(reader)?;
+    if let GameDataSource::File(path) = source {
+        match game_data::load_file(path) {
+            Ok(db) => {
+                tracing::info!("Using game data from {path:?}");
+                return Ok(db);
+            }
+            Err(e) => tracing::error!("Error loading game data file, using embedded data: {e:#}"),
+        }
+        return game_data::load_embedded();
+    }
 
-    Ok(db)
+    match game_data::check_for_update(source).await {
+        Ok(Some(update)) => {
+            let confirmation_type = if game_data::has_cached() {
+                ConfirmationType::Update
+            } else {
+                ConfirmationType::Initial
+            };
+            app_state.update_app_state(State::WaitingForDownloadConfirmation(confirmation_type));
+
+            let acknowledged = loop {
+                match ui_message_rx.recv().await {
+                    Some(Message::DownloadAcknowledged) => break true,
+                    Some(Message::DownloadCanceled) => {
+                        if let Err(e) = update.skip() {
+                            tracing::warn!("Unable to remember skipped game data update: {e:#}");
+                        }
+                        break false;
+                    }
+                    None => break false,
+                    Some(msg) => queued_ui_messages.push(msg),
+                }
+            };
+
+            if acknowledged {
+                app_state.update_app_state(State::Downloading);
+                match game_data::download_update(update).await {
+                    Ok(db) => return Ok(db),
+                    Err(e) => tracing::error!("Error downloading game data: {e:#}"),
+                }
+            }
+        }
+        Ok(None) => (),
+        Err(e) => tracing::warn!("Unable to check for game data update: {e:#}"),
+    }
+
+    match game_data::load_cached() {
+        Ok(Some(db)) => {
+            tracing::info!("Using cached game data");
+            return Ok(db);
+        }
+        Ok(None) => (),
+        Err(e) => tracing::warn!("Unable to load cached game data: {e:#}"),
+    }
+
+    tracing::info!("Using embedded game data");
+    game_data::load_embedded()
 }
 
+/// Run the capture until it is cancelled, restarting it when it fails in a
+/// way that may be temporary, e.g. an interface going away.
 async fn capture_task(
     cancel_token: CancellationToken,
-    packet_tx: mpsc::UnboundedSender<Vec<u8>>,
+    packet_tx: PacketSender<CapturedPacket>,
+    event_tx: CaptureEventSender,
+    queue_stats: Arc<QueueStats>,
     backend: BackendType,
-) -> Result<()> {
-    let mut capture = create_capture(backend)
-        .map_err(|e| anyhow!("Error creating packet capture using {:?}: {e}", backend))?;
-    tracing::info!("starting capture");
+    config: CaptureConfig,
+) {
+    let mut failures = 0;
+    let mut delay = CAPTURE_RESTART_DELAY;
     loop {
+        let (end, received) = capture_run(
+            &cancel_token,
+            &packet_tx,
+            &event_tx,
+            &queue_stats,
+            backend,
+            &config,
+        )
+        .await;
+        let error = match end {
+            CaptureEnd::Cancelled => return,
+            CaptureEnd::Fatal(error) => {
+                event_tx.send(CaptureEvent::Failed(error));
+                return;
+            }
+            CaptureEnd::Restart(error) => error,
+        };
+
+        if received {
+            failures = 0;
+            delay = CAPTURE_RESTART_DELAY;
+        }
+        failures += 1;
+        if failures > CAPTURE_MAX_RESTARTS {
+            event_tx.send(CaptureEvent::Failed(format!(
+                "{error} (gave up after {CAPTURE_MAX_RESTARTS} restarts)"
+            )));
+            return;
+        }
+
+        tracing::warn!("Restarting capture in {delay:?}: {error}");
+        event_tx.send(CaptureEvent::Restarting(error));
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = cancel_token.cancelled() => return,
+        }
+        delay = (delay * 2).min(CAPTURE_RESTART_MAX_DELAY);
+    }
+}
+
+/// Create a capture backend, which enumerates devices afresh, and forward its
+/// packets until it ends.  Also returns whether any packet was received.
+async fn capture_run(
+    cancel_token: &CancellationToken,
+    packet_tx: &PacketSender<CapturedPacket>,
+    event_tx: &CaptureEventSender,
+    queue_stats: &Arc<QueueStats>,
+    backend: BackendType,
+    config: &CaptureConfig,
+) -> (CaptureEnd, bool) {
+    let mut capture = match create_capture(backend, config, queue_stats.clone()) {
+        Ok(capture) => capture,
+        // A filter that doesn't compile, a denied authorization or a backend
+        // that can't work here won't get better by retrying.
+        Err(
+            e @ (CaptureError::Filter(_)
+            | CaptureError::Permission(_)
+            | CaptureError::Unsupported(_)),
+        ) => {
+            return (
+                CaptureEnd::Fatal(format!(
+                    "Error creating packet capture using {backend:?}: {e}"
+                )),
+                false,
+            );
+        }
+        Err(e) => {
+            return (
+                CaptureEnd::Restart(format!(
+                    "Error creating packet capture using {backend:?}: {e}"
+                )),
+                false,
+            );
+        }
+    };
+    tracing::info!("starting capture");
+
+    let mut reassembler = Reassembler::new();
+    let mut deduplicator = Deduplicator::new();
+    let mut received = false;
+    let mut last_error = None;
+    let end = loop {
         let packet = tokio::select!(
             packet = capture.next_packet() => packet,
-            _ = cancel_token.cancelled() => break,
+            _ = cancel_token.cancelled() => break CaptureEnd::Cancelled,
         );
         let packet = match packet {
             Ok(packet) => packet,
-            Err(e) => {
-                tracing::error!("Error receiving packet: {e}");
+            Err(
+                e @ (CaptureError::Filter(_)
+                | CaptureError::Permission(_)
+                | CaptureError::Unsupported(_)),
+            ) => {
+                break CaptureEnd::Fatal(e.to_string());
+            }
+            // A device that never worked.  Others may still be capturing; once
+            // they've all failed the capture closes.
+            Err(CaptureError::Capture {
+                has_captured: false,
+                error,
+            }) => {
+                tracing::warn!("Capture device failed: {error}");
+                last_error = Some(error.to_string());
                 continue;
             }
+            // A device that was working went away, e.g. an interface that
+            // was unplugged or reconfigured.
+            Err(CaptureError::Capture {
+                has_captured: true,
+                error,
+            }) => break CaptureEnd::Restart(format!("Capture device stopped: {error}")),
+            // Nothing more will ever arrive on stdin.
+            Err(CaptureError::CaptureClosed) if backend == BackendType::Stdin => {
+                break CaptureEnd::Fatal(
+                    last_error.unwrap_or_else(|| "End of input stream".to_string()),
+                );
+            }
+            Err(e @ (CaptureError::CaptureClosed | CaptureError::ChannelClosed)) => {
+                break CaptureEnd::Restart(match last_error {
+                    Some(error) => format!("{e}: {error}"),
+                    None => e.to_string(),
+                });
+            }
         };
 
-        if let Err(e) = packet_tx.send(packet) {
-            tracing::error!("Error sending captured packet to monitor: {e}");
+        if !received {
+            received = true;
+            event_tx.send(CaptureEvent::Running);
         }
-    }
-    tracing::info!("ending capture");
-    Ok(())
-}
-
-fn log_command(command: &GameCommand) -> Result<()> {
-    let mut packet_log_path = eframe::storage_dir(APP_ID).context("Storage dir not found")?;
-    packet_log_path.push("packet_log");
-    fs::create_dir_all(&packet_log_path)?;
-
-    let now = Local::now();
-    packet_log_path.push(format!(
-        "{}-{}.bin",
-        now.format("%Y-%m-%d_%H-%M-%S%.f"),
-        command.command_id
-    ));
 
-    let file = fs::File::create(&packet_log_path)
-        .with_context(|| format!("can't create file {packet_log_path:?}"))?;
-    let mut writer = BufWriter::new(file);
-    writer.write_all(&command.proto_data)?;
+        let Some(packet) = reassembler.process(packet) else {
+            continue;
+        };
 
-    Ok(())
-}
+        if !deduplicator.check(&packet) {
+            tracing::trace!("Dropping duplicate packet");
+            continue;
+        }
 
-fn load_keys() -> Result<HashMap<u16, Vec<u8>>> {
-    let keys: HashMap<u16, String> = serde_json::from_slice(include_bytes!("../keys/gi.json"))?;
+        // Waiting here pushes back into the backend's queue, where drops are
+        // counted.
+        let sent = tokio::select!(
+            sent = packet_tx.send(packet) => sent,
+            _ = cancel_token.cancelled() => break CaptureEnd::Cancelled,
+        );
+        if sent.is_err() {
+            tracing::error!("Error sending captured packet to monitor: queue closed");
+        }
+    };
+    tracing::info!(
+        "ending capture, reassembled {} datagrams ({} incomplete), dropped {} duplicate packets",
+        reassembler.reassembled(),
+        reassembler.expired(),
+        deduplicator.duplicates()
+    );
 
-    keys.iter()
-        .map(|(key, value)| -> Result<_, _> { Ok((*key, BASE64_STANDARD.decode(value)?)) })
-        .collect::<Result<HashMap<_, _>>>()
+    (end, received)
 }
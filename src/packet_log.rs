@@ -0,0 +1,525 @@
+//! Per-session archive of the game commands seen while capturing.
+//!
+//! A session is written to `packet_log/<start time>.pktlog`, which starts
+//! with `LOG_MAGIC` and `VERSION` followed by one record per command:
+//!
+//! `[len u32][timestamp i64][command id u32][direction u8][payload]`
+//!
+//! All integers are little endian, `len` counts the bytes after itself and
+//! the timestamp is in microseconds since the Unix epoch.  Next to it,
+//! `<start time>.pktidx` holds `INDEX_MAGIC` and `VERSION` followed by a
+//! fixed size entry per record, `[offset u64][timestamp i64][command id u32]`,
+//! so records can be found even after a damaged one.  With
+//! decoding enabled, the commands Irminsul understands are also written to
+//! `<start time>.jsonl`, with field names where Irminsul knows the message
+//! type and field numbers otherwise.
+//!
+//! Earlier versions wrote one `<time>-<command id>.bin` file per command
+//! instead.  Both can be read back for replay.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, bail};
+use auto_artifactarium::GameCommand;
+use chrono::prelude::*;
+use serde_json::{Value, json};
+
+use crate::APP_ID;
+use crate::capture::Direction;
+
+pub mod proto;
+
+pub const LOG_MAGIC: &[u8; 8] = b"IRMPKLOG";
+pub const INDEX_MAGIC: &[u8; 8] = b"IRMPKIDX";
+pub const VERSION: u32 = 1;
+
+pub const LOG_EXTENSION: &str = "pktlog";
+pub const INDEX_EXTENSION: &str = "pktidx";
+pub const DECODED_EXTENSION: &str = "jsonl";
+
+/// Size of a record's fields between `len` and the payload.
+pub const RECORD_HEADER_LEN: usize = 8 + 4 + 1;
+
+/// Size of an index entry.
+pub const INDEX_ENTRY_LEN: usize = 8 + 8 + 4;
+
+/// What the power tools packet logging settings ask for.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PacketLogSettings {
+    pub enabled: bool,
+    /// Also write a JSON decode of the commands Irminsul understands.
+    pub decode: bool,
+}
+
+/// One logged game command.
+#[derive(Clone, Debug)]
+pub struct LogRecord {
+    pub timestamp: SystemTime,
+    pub command_id: u32,
+    pub direction: Direction,
+    pub payload: Vec<u8>,
+}
+
+impl LogRecord {
+    /// The command as the sniffer would have decoded it, for replay.
+    pub fn into_command(self) -> Result<GameCommand> {
+        Ok(GameCommand {
+            command_id: self
+                .command_id
+                .try_into()
+                .context("command id out of range")?,
+            header_len: 0,
+            data_len: self.payload.len() as u32,
+            proto_data: self.payload,
+        })
+    }
+}
+
+pub fn direction_to_byte(direction: Direction) -> u8 {
+    match direction {
+        Direction::Unknown => 0,
+        Direction::ClientToServer => 1,
+        Direction::ServerToClient => 2,
+    }
+}
+
+pub fn direction_from_byte(byte: u8) -> Direction {
+    match byte {
+        1 => Direction::ClientToServer,
+        2 => Direction::ServerToClient,
+        _ => Direction::Unknown,
+    }
+}
+
+pub fn timestamp_to_micros(timestamp: SystemTime) -> i64 {
+    match timestamp.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_micros() as i64,
+        Err(e) => -(e.duration().as_micros() as i64),
+    }
+}
+
+pub fn micros_to_timestamp(micros: i64) -> SystemTime {
+    let since = Duration::from_micros(micros.unsigned_abs());
+    if micros >= 0 {
+        UNIX_EPOCH + since
+    } else {
+        UNIX_EPOCH - since
+    }
+}
+
+pub fn packet_log_dir() -> Result<PathBuf> {
+    let mut dir = eframe::storage_dir(APP_ID).context("Storage dir not found")?;
+    dir.push("packet_log");
+    Ok(dir)
+}
+
+/// Create a file that must not exist yet, so an earlier session is never
+/// overwritten.
+fn create_new_file(path: &Path) -> std::io::Result<BufWriter<File>> {
+    let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+    #[cfg(unix)]
+    crate::admin::chown_to_sudo_user(path);
+    Ok(BufWriter::new(file))
+}
+
+/// Writes the archive, index and optional JSON decode of one session.
+pub struct PacketLogWriter {
+    path: PathBuf,
+    log: BufWriter<File>,
+    index: BufWriter<File>,
+    decoded: Option<BufWriter<File>>,
+    offset: u64,
+}
+
+impl PacketLogWriter {
+    /// Start a new session archive named after the current time.
+    pub fn create() -> Result<Self> {
+        Self::create_in(&packet_log_dir()?)
+    }
+
+    fn create_in(dir: &Path) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("can't create directory {dir:?}"))?;
+        #[cfg(unix)]
+        crate::admin::chown_to_sudo_user(dir);
+
+        // Sessions may start within the same millisecond, e.g. when capture is
+        // stopped and started again right away.
+        let name = Local::now().format("%Y-%m-%d_%H-%M-%S-%3f").to_string();
+        let (path, log) = (0..)
+            .map(|n| match n {
+                0 => dir.join(&name),
+                n => dir.join(format!("{name}-{n}")),
+            })
+            .find_map(
+                |path| match create_new_file(&path.with_extension(LOG_EXTENSION)) {
+                    Err(e) if e.kind() == ErrorKind::AlreadyExists => None,
+                    log => Some((path, log)),
+                },
+            )
+            .context("no free packet log name")?;
+        let log_path = path.with_extension(LOG_EXTENSION);
+        let mut log = log.with_context(|| format!("can't create file {log_path:?}"))?;
+        let index_path = path.with_extension(INDEX_EXTENSION);
+        let mut index = create_new_file(&index_path)
+            .with_context(|| format!("can't create file {index_path:?}"))?;
+        for (file, magic) in [(&mut log, LOG_MAGIC), (&mut index, INDEX_MAGIC)] {
+            file.write_all(magic)?;
+            file.write_all(&VERSION.to_le_bytes())?;
+        }
+        tracing::info!("Logging packets to {path:?}");
+
+        Ok(Self {
+            path,
+            log,
+            index,
+            decoded: None,
+            offset: (LOG_MAGIC.len() + 4) as u64,
+        })
+    }
+
+    /// Append `record`.  If `message` names the kind of command, a JSON
+    /// decode of it is written as well, using `fields` if given.  After an
+    /// error the archive may hold part of the record, so the writer must not
+    /// be used any further.
+    pub fn write(
+        &mut self,
+        record: &LogRecord,
+        message: Option<&str>,
+        fields: Option<Value>,
+    ) -> Result<()> {
+        let timestamp = timestamp_to_micros(record.timestamp);
+        let len = (RECORD_HEADER_LEN + record.payload.len()) as u32;
+        self.log.write_all(&len.to_le_bytes())?;
+        self.log.write_all(&timestamp.to_le_bytes())?;
+        self.log.write_all(&record.command_id.to_le_bytes())?;
+        self.log.write_all(&[direction_to_byte(record.direction)])?;
+        self.log.write_all(&record.payload)?;
+
+        self.index.write_all(&self.offset.to_le_bytes())?;
+        self.index.write_all(&timestamp.to_le_bytes())?;
+        self.index.write_all(&record.command_id.to_le_bytes())?;
+
+        if let Some(message) = message {
+            self.write_decoded(record, message, fields)?;
+        }
+
+        self.offset += 4 + u64::from(len);
+        Ok(())
+    }
+
+    fn write_decoded(
+        &mut self,
+        record: &LogRecord,
+        message: &str,
+        fields: Option<Value>,
+    ) -> Result<()> {
+        let fields = fields.unwrap_or_else(|| {
+            proto::decode_message(&record.payload)
+                .map(|fields| proto::fields_to_json(&fields))
+                .unwrap_or_else(|| proto::hex(&record.payload).into())
+        });
+        let line = json!({
+            "offset": self.offset,
+            "timestamp": DateTime::<Local>::from(record.timestamp).to_rfc3339(),
+            "command_id": record.command_id,
+            "direction": record.direction.to_string(),
+            "message": message,
+            "fields": fields,
+        });
+
+        if self.decoded.is_none() {
+            let path = self.path.with_extension(DECODED_EXTENSION);
+            let decoded =
+                create_new_file(&path).with_context(|| format!("can't create file {path:?}"))?;
+            self.decoded = Some(decoded);
+        }
+        if let Some(decoded) = &mut self.decoded {
+            serde_json::to_writer(&mut *decoded, &line)?;
+            decoded.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+
+    /// Flush everything written so far, so the files are usable while the
+    /// session is still running.
+    pub fn flush(&mut self) -> Result<()> {
+        self.log.flush()?;
+        self.index.flush()?;
+        if let Some(decoded) = &mut self.decoded {
+            decoded.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse the record at the start of `data`, returning it and its length.
+fn parse_record(data: &[u8]) -> Option<(LogRecord, usize)> {
+    let len = u32::from_le_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let body = data.get(4..4 + len)?;
+    if len < RECORD_HEADER_LEN {
+        return None;
+    }
+
+    let record = LogRecord {
+        timestamp: micros_to_timestamp(i64::from_le_bytes(body[..8].try_into().ok()?)),
+        command_id: u32::from_le_bytes(body[8..12].try_into().ok()?),
+        direction: direction_from_byte(body[12]),
+        payload: body[RECORD_HEADER_LEN..].to_vec(),
+    };
+    Some((record, 4 + len))
+}
+
+/// Check the magic and version at the start of `data`, returning the length
+/// of the header.
+fn check_header(data: &[u8], magic: &[u8; 8], path: &Path) -> Result<usize> {
+    let header_len = magic.len() + 4;
+    if data.len() < header_len || &data[..magic.len()] != magic {
+        bail!("{path:?} is not a packet log");
+    }
+    let version = u32::from_le_bytes(data[magic.len()..header_len].try_into()?);
+    if version != VERSION {
+        bail!("{path:?} has unsupported packet log version {version}");
+    }
+    Ok(header_len)
+}
+
+/// One record's entry in a session index.
+struct IndexEntry {
+    offset: u64,
+    timestamp: i64,
+    command_id: u32,
+}
+
+fn read_index(path: &Path) -> Result<Vec<IndexEntry>> {
+    let data = fs::read(path).with_context(|| format!("can't read file {path:?}"))?;
+    let header_len = check_header(&data, INDEX_MAGIC, path)?;
+    // A partly written entry at the end is left for the scan to find.
+    data[header_len..]
+        .chunks_exact(INDEX_ENTRY_LEN)
+        .map(|entry| {
+            Ok(IndexEntry {
+                offset: u64::from_le_bytes(entry[..8].try_into()?),
+                timestamp: i64::from_le_bytes(entry[8..16].try_into()?),
+                command_id: u32::from_le_bytes(entry[16..].try_into()?),
+            })
+        })
+        .collect()
+}
+
+/// Read every record of a session archive.  Records listed in the index are
+/// read from their offsets, so one damaged record doesn't hide the ones after
+/// it.  Records the index doesn't cover yet, or all of them without an index,
+/// are found by scanning the archive.
+fn read_archive(path: &Path) -> Result<Vec<LogRecord>> {
+    let data = fs::read(path).with_context(|| format!("can't read file {path:?}"))?;
+    let header_len = check_header(&data, LOG_MAGIC, path)?;
+    let index = read_index(&path.with_extension(INDEX_EXTENSION)).unwrap_or_else(|e| {
+        tracing::warn!("Reading packet log without its index: {e:#}");
+        Vec::new()
+    });
+
+    let mut records = Vec::new();
+    // Where the records after the indexed ones start, unless the last indexed
+    // record was damaged and its end is unknown.
+    let mut scan_from = Some(header_len);
+    for entry in &index {
+        let record = usize::try_from(entry.offset)
+            .ok()
+            .and_then(|offset| Some((offset, parse_record(data.get(offset..)?)?)));
+        match record {
+            Some((offset, (record, len)))
+                if timestamp_to_micros(record.timestamp) == entry.timestamp
+                    && record.command_id == entry.command_id =>
+            {
+                records.push(record);
+                scan_from = Some(offset + len);
+            }
+            _ => {
+                tracing::warn!(
+                    "Skipping damaged record at offset {} of {path:?}",
+                    entry.offset
+                );
+                scan_from = None;
+            }
+        }
+    }
+
+    let Some(mut offset) = scan_from else {
+        return Ok(records);
+    };
+    while offset < data.len() {
+        let Some((record, len)) = parse_record(&data[offset..]) else {
+            // Irminsul was closed while writing the record.
+            tracing::warn!("Ignoring truncated record at offset {offset} of {path:?}");
+            break;
+        };
+        records.push(record);
+        offset += len;
+    }
+    Ok(records)
+}
+
+/// Read a `<time>-<command id>.bin` file written by earlier versions.
+fn read_legacy_file(path: &Path) -> Result<LogRecord> {
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .with_context(|| format!("invalid packet file name {path:?}"))?;
+    let (time, command_id) = stem
+        .rsplit_once('-')
+        .with_context(|| format!("invalid packet file name {path:?}"))?;
+    let command_id = command_id
+        .parse()
+        .with_context(|| format!("invalid command id in {path:?}"))?;
+    let timestamp = NaiveDateTime::parse_from_str(time, "%Y-%m-%d_%H-%M-%S%.f")
+        .ok()
+        .and_then(|time| Local.from_local_datetime(&time).earliest())
+        .map(SystemTime::from)
+        .unwrap_or(UNIX_EPOCH);
+
+    Ok(LogRecord {
+        timestamp,
+        command_id,
+        direction: Direction::Unknown,
+        payload: fs::read(path).with_context(|| format!("can't read file {path:?}"))?,
+    })
+}
+
+/// Read all the `.bin` files in `dir`, oldest first.
+fn read_legacy_dir(dir: &Path) -> Result<Vec<LogRecord>> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("can't read directory {dir:?}"))? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "bin") {
+            paths.push(path);
+        }
+    }
+    if paths.is_empty() {
+        bail!("no packet files found in {dir:?}");
+    }
+
+    let mut records = paths
+        .iter()
+        .filter_map(|path| {
+            read_legacy_file(path)
+                .inspect_err(|e| tracing::warn!("Skipping packet file: {e:#}"))
+                .ok()
+        })
+        .collect::<Vec<_>>();
+    records.sort_by_key(|record| record.timestamp);
+    Ok(records)
+}
+
+/// Read the commands logged to `path` for replay.  `path` is either a session
+/// archive, or a directory of `.bin` files or one of those files, in which
+/// case the whole directory is read.
+pub fn read_records(path: &Path) -> Result<Vec<LogRecord>> {
+    if path.is_dir() {
+        return read_legacy_dir(path);
+    }
+    if path.extension().is_some_and(|extension| extension == "bin") {
+        let dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        return read_legacy_dir(dir);
+    }
+    read_archive(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(command_id: u32, direction: Direction, payload: &[u8]) -> LogRecord {
+        LogRecord {
+            timestamp: UNIX_EPOCH
+                + Duration::from_micros(1_700_000_000_123_456 + u64::from(command_id)),
+            command_id,
+            direction,
+            payload: payload.to_vec(),
+        }
+    }
+
+    fn assert_records_eq(read: &[LogRecord], written: &[LogRecord]) {
+        assert_eq!(read.len(), written.len());
+        for (read, written) in read.iter().zip(written) {
+            assert_eq!(read.timestamp, written.timestamp);
+            assert_eq!(read.command_id, written.command_id);
+            assert_eq!(read.direction, written.direction);
+            assert_eq!(read.payload, written.payload);
+        }
+    }
+
+    /// Write `records` to a new session in `dir`, returning the archive's
+    /// path.
+    fn write_session(dir: &Path, records: &[LogRecord]) -> PathBuf {
+        let mut writer = PacketLogWriter::create_in(dir).unwrap();
+        for record in records {
+            writer.write(record, None, None).unwrap();
+        }
+        writer.flush().unwrap();
+        writer.path.with_extension(LOG_EXTENSION)
+    }
+
+    fn sample_records() -> Vec<LogRecord> {
+        vec![
+            record(1, Direction::ClientToServer, b"first"),
+            record(2, Direction::ServerToClient, &[]),
+            record(3, Direction::Unknown, &[0xff; 300]),
+        ]
+    }
+
+    #[test]
+    fn round_trips_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let records = sample_records();
+        let path = write_session(dir.path(), &records);
+
+        assert_records_eq(&read_records(&path).unwrap(), &records);
+    }
+
+    #[test]
+    fn reads_records_missing_from_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let records = sample_records();
+        let path = write_session(dir.path(), &records);
+
+        // Only the first record made it into the index, the last entry only
+        // partly.
+        let index_path = path.with_extension(INDEX_EXTENSION);
+        let index = fs::read(&index_path).unwrap();
+        fs::write(&index_path, &index[..12 + INDEX_ENTRY_LEN + 5]).unwrap();
+        assert_records_eq(&read_records(&path).unwrap(), &records);
+
+        fs::remove_file(&index_path).unwrap();
+        assert_records_eq(&read_records(&path).unwrap(), &records);
+    }
+
+    #[test]
+    fn skips_damaged_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let records = sample_records();
+        let path = write_session(dir.path(), &records);
+
+        // Corrupt the second record's length so scanning can't get past it.
+        let mut data = fs::read(&path).unwrap();
+        let second = 12 + 4 + RECORD_HEADER_LEN + records[0].payload.len();
+        data[second..second + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, data).unwrap();
+
+        let read = read_records(&path).unwrap();
+        assert_records_eq(&read, &[records[0].clone(), records[2].clone()]);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("other.pktlog");
+        fs::write(&path, b"not a packet log").unwrap();
+        assert!(read_records(&path).is_err());
+    }
+}
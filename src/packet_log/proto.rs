@@ -0,0 +1,424 @@
+use std::collections::BTreeMap;
+
+use protobuf::MessageDyn;
+use protobuf::reflect::{ReflectFieldRef, ReflectValueRef};
+use serde_json::{Map, Value, json};
+
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LEN: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+/// Nesting depth beyond which length-delimited fields are left as bytes.
+const MAX_DEPTH: usize = 32;
+
+/// A protobuf field decoded without a schema.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub number: u32,
+    pub value: FieldValue,
+}
+
+/// The value of a field, guessed from its wire type.  Length-delimited fields
+/// are a nested message if they parse as one and text if they look like it,
+/// or both when they could be either, otherwise bytes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    Varint(u64),
+    Fixed64(u64),
+    Fixed32(u32),
+    String(String),
+    Message(Vec<Field>),
+    /// Printable text that also parses as a message, e.g. short strings or
+    /// messages of a few small fields.
+    TextOrMessage(String, Vec<Field>),
+    Bytes(Vec<u8>),
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos)?;
+        *pos += 1;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn read_bytes<'a>(data: &'a [u8], pos: &mut usize, len: usize) -> Option<&'a [u8]> {
+    let bytes = data.get(*pos..pos.checked_add(len)?)?;
+    *pos += len;
+    Some(bytes)
+}
+
+fn looks_like_text(data: &[u8]) -> Option<&str> {
+    let text = std::str::from_utf8(data).ok()?;
+    let printable = text
+        .chars()
+        .all(|c| !c.is_control() || c == '\n' || c == '\r' || c == '\t');
+    printable.then_some(text)
+}
+
+fn decode_len(data: &[u8], depth: usize) -> FieldValue {
+    if data.is_empty() {
+        return FieldValue::Bytes(Vec::new());
+    }
+    let fields = if depth < MAX_DEPTH {
+        decode_fields(data, depth + 1)
+    } else {
+        None
+    };
+    match (fields, looks_like_text(data)) {
+        (Some(fields), Some(text)) => FieldValue::TextOrMessage(text.to_string(), fields),
+        (Some(fields), None) => FieldValue::Message(fields),
+        (None, Some(text)) => FieldValue::String(text.to_string()),
+        (None, None) => FieldValue::Bytes(data.to_vec()),
+    }
+}
+
+fn decode_fields(data: &[u8], depth: usize) -> Option<Vec<Field>> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let key = read_varint(data, &mut pos)?;
+        let number = u32::try_from(key >> 3).ok().filter(|number| *number != 0)?;
+        let value = match key & 0x7 {
+            WIRE_VARINT => FieldValue::Varint(read_varint(data, &mut pos)?),
+            WIRE_FIXED64 => {
+                let bytes = read_bytes(data, &mut pos, 8)?;
+                FieldValue::Fixed64(u64::from_le_bytes(bytes.try_into().ok()?))
+            }
+            WIRE_LEN => {
+                let len = usize::try_from(read_varint(data, &mut pos)?).ok()?;
+                decode_len(read_bytes(data, &mut pos, len)?, depth)
+            }
+            WIRE_FIXED32 => {
+                let bytes = read_bytes(data, &mut pos, 4)?;
+                FieldValue::Fixed32(u32::from_le_bytes(bytes.try_into().ok()?))
+            }
+            // Groups are deprecated and never used by the game.
+            _ => return None,
+        };
+        fields.push(Field { number, value });
+    }
+    Some(fields)
+}
+
+impl FieldValue {
+    /// The protobuf wire type the value was encoded with.
+    pub fn wire_type(&self) -> &'static str {
+        match self {
+            FieldValue::Varint(_) => "varint",
+            FieldValue::Fixed64(_) => "i64",
+            FieldValue::Fixed32(_) => "i32",
+            FieldValue::String(_)
+            | FieldValue::Message(_)
+            | FieldValue::TextOrMessage(..)
+            | FieldValue::Bytes(_) => "len",
+        }
+    }
+}
+
+/// Decode `data` as a packed repeated varint field, or `None` if it isn't one.
+pub fn decode_packed_varints(data: &[u8]) -> Option<Vec<u64>> {
+    let mut values = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        values.push(read_varint(data, &mut pos)?);
+    }
+    Some(values)
+}
+
+/// Decode `data` as a protobuf message, or `None` if it isn't one.
+pub fn decode_message(data: &[u8]) -> Option<Vec<Field>> {
+    decode_fields(data, 0)
+}
+
+fn value_to_json(value: &FieldValue) -> Value {
+    match value {
+        FieldValue::Varint(value) | FieldValue::Fixed64(value) => Value::from(*value),
+        FieldValue::Fixed32(value) => Value::from(*value),
+        FieldValue::String(text) => Value::from(text.as_str()),
+        FieldValue::Message(fields) => fields_to_json(fields),
+        FieldValue::TextOrMessage(text, fields) => {
+            json!({"text": text, "message": fields_to_json(fields)})
+        }
+        FieldValue::Bytes(bytes) => Value::from(hex(bytes)),
+    }
+}
+
+/// JSON object keyed by field number.  Repeated fields become arrays.
+pub fn fields_to_json(fields: &[Field]) -> Value {
+    let mut counts = BTreeMap::<u32, usize>::new();
+    for field in fields {
+        *counts.entry(field.number).or_default() += 1;
+    }
+
+    let mut object = Map::new();
+    for field in fields {
+        let key = field.number.to_string();
+        let value = value_to_json(&field.value);
+        if counts[&field.number] == 1 {
+            object.insert(key, value);
+        } else if let Value::Array(values) = object
+            .entry(key)
+            .or_insert_with(|| Value::Array(Vec::new()))
+        {
+            values.push(value);
+        }
+    }
+    Value::Object(object)
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn reflect_value_to_json(value: ReflectValueRef<'_>) -> Value {
+    match value {
+        ReflectValueRef::U32(value) => Value::from(value),
+        ReflectValueRef::U64(value) => Value::from(value),
+        ReflectValueRef::I32(value) => Value::from(value),
+        ReflectValueRef::I64(value) => Value::from(value),
+        ReflectValueRef::F32(value) => Value::from(value),
+        ReflectValueRef::F64(value) => Value::from(value),
+        ReflectValueRef::Bool(value) => Value::from(value),
+        ReflectValueRef::String(text) => Value::from(text),
+        ReflectValueRef::Bytes(bytes) => Value::from(hex(bytes)),
+        ReflectValueRef::Enum(descriptor, number) => match descriptor.value_by_number(number) {
+            Some(value) => Value::from(value.name()),
+            None => Value::from(number),
+        },
+        ReflectValueRef::Message(message) => message_to_json(&*message),
+    }
+}
+
+fn map_key_to_string(key: ReflectValueRef<'_>) -> String {
+    match key {
+        ReflectValueRef::String(text) => text.to_string(),
+        key => reflect_value_to_json(key).to_string(),
+    }
+}
+
+/// JSON object of a typed message keyed by field name.  Like the protobuf
+/// JSON mapping, fields that aren't set are left out.
+pub fn message_to_json(message: &dyn MessageDyn) -> Value {
+    let mut object = Map::new();
+    for field in message.descriptor_dyn().fields() {
+        let value = match field.get_reflect(message) {
+            ReflectFieldRef::Optional(value) => match value.value() {
+                Some(value) => reflect_value_to_json(value),
+                None => continue,
+            },
+            ReflectFieldRef::Repeated(values) => {
+                if values.is_empty() {
+                    continue;
+                }
+                Value::Array(values.into_iter().map(reflect_value_to_json).collect())
+            }
+            ReflectFieldRef::Map(entries) => {
+                if entries.is_empty() {
+                    continue;
+                }
+                Value::Object(
+                    entries
+                        .into_iter()
+                        .map(|(key, value)| (map_key_to_string(key), reflect_value_to_json(value)))
+                        .collect(),
+                )
+            }
+        };
+        object.insert(field.name().to_string(), value);
+    }
+    Value::Object(object)
+}
+
+/// JSON array of typed messages.
+pub fn messages_to_json<M: MessageDyn>(messages: &[M]) -> Value {
+    Value::Array(
+        messages
+            .iter()
+            .map(|message| message_to_json(message))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn varint(mut value: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        while value >= 0x80 {
+            bytes.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        bytes.push(value as u8);
+        bytes
+    }
+
+    fn len_field(number: u32, data: &[u8]) -> Vec<u8> {
+        let mut field = varint((u64::from(number) << 3) | WIRE_LEN);
+        field.extend(varint(data.len() as u64));
+        field.extend_from_slice(data);
+        field
+    }
+
+    #[test]
+    fn decodes_scalars() {
+        let mut data = vec![0x08, 0x96, 0x01];
+        data.push(0x11);
+        data.extend_from_slice(&1.5f64.to_bits().to_le_bytes());
+        data.push(0x1d);
+        data.extend_from_slice(&7u32.to_le_bytes());
+
+        assert_eq!(
+            decode_message(&data),
+            Some(vec![
+                Field {
+                    number: 1,
+                    value: FieldValue::Varint(150),
+                },
+                Field {
+                    number: 2,
+                    value: FieldValue::Fixed64(1.5f64.to_bits()),
+                },
+                Field {
+                    number: 3,
+                    value: FieldValue::Fixed32(7),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn decodes_length_delimited_fields() {
+        let mut data = len_field(1, b"hello");
+        data.extend(len_field(2, &[0x08, 0x01]));
+        data.extend(len_field(3, &[0xff, 0xfe]));
+        data.extend(len_field(4, &[]));
+
+        assert_eq!(
+            decode_message(&data),
+            Some(vec![
+                Field {
+                    number: 1,
+                    value: FieldValue::String("hello".to_string()),
+                },
+                Field {
+                    number: 2,
+                    value: FieldValue::Message(vec![Field {
+                        number: 1,
+                        value: FieldValue::Varint(1),
+                    }]),
+                },
+                Field {
+                    number: 3,
+                    value: FieldValue::Bytes(vec![0xff, 0xfe]),
+                },
+                Field {
+                    number: 4,
+                    value: FieldValue::Bytes(Vec::new()),
+                },
+            ])
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        // Truncated varint.
+        assert_eq!(decode_message(&[0x08, 0x80]), None);
+        // Varint longer than 10 bytes.
+        let mut data = vec![0x08];
+        data.extend_from_slice(&[0xff; 10]);
+        data.push(0x01);
+        assert_eq!(decode_message(&data), None);
+        // Field number 0.
+        assert_eq!(decode_message(&[0x00, 0x01]), None);
+        // Field number beyond u32.
+        let mut data = varint(1 << 35);
+        data.push(0x01);
+        assert_eq!(decode_message(&data), None);
+        // Length past the end of the message.
+        assert_eq!(decode_message(&[0x0a, 0x05, 0x01]), None);
+        assert_eq!(decode_message(&[0x0a, 0xff, 0xff, 0xff, 0xff, 0x0f]), None);
+        // Truncated fixed width values.
+        assert_eq!(decode_message(&[0x11, 0x01, 0x02]), None);
+        assert_eq!(decode_message(&[0x1d, 0x01]), None);
+        // Group wire types.
+        assert_eq!(decode_message(&[0x0b]), None);
+        assert_eq!(decode_message(&[0x0c]), None);
+    }
+
+    #[test]
+    fn limits_nesting_depth() {
+        let mut data = vec![0x08, 0x01];
+        for _ in 0..MAX_DEPTH + 8 {
+            data = len_field(1, &data);
+        }
+
+        let fields = decode_message(&data).unwrap();
+        let mut value = &fields[0].value;
+        let mut depth = 0;
+        while let FieldValue::Message(fields) = value {
+            depth += 1;
+            value = &fields[0].value;
+        }
+        assert_eq!(depth, MAX_DEPTH);
+        assert!(matches!(value, FieldValue::Bytes(_)));
+    }
+
+    #[test]
+    fn decodes_packed_varints() {
+        assert_eq!(
+            decode_packed_varints(&[0x01, 0x96, 0x01]),
+            Some(vec![1, 150])
+        );
+        assert_eq!(decode_packed_varints(&[]), Some(Vec::new()));
+        assert_eq!(decode_packed_varints(&[0x96]), None);
+    }
+
+    #[test]
+    fn keeps_both_readings_of_ambiguous_fields() {
+        // Field 5 = 100, which is also the text "(d".
+        let data = len_field(1, &[0x28, 0x64]);
+
+        assert_eq!(
+            decode_message(&data),
+            Some(vec![Field {
+                number: 1,
+                value: FieldValue::TextOrMessage(
+                    "(d".to_string(),
+                    vec![Field {
+                        number: 5,
+                        value: FieldValue::Varint(100),
+                    }]
+                ),
+            }])
+        );
+    }
+
+    #[test]
+    fn converts_fields_to_json() {
+        let mut data = vec![0x08, 0x01, 0x08, 0x02];
+        data.extend(len_field(2, b"hello"));
+        data.extend(len_field(3, &[0xff]));
+        data.extend(len_field(4, b"hi"));
+
+        let fields = decode_message(&data).unwrap();
+        assert_eq!(
+            fields_to_json(&fields),
+            json!({
+                "1": [1, 2],
+                "2": "hello",
+                "3": "ff",
+                "4": {"text": "hi", "message": {"13": 105}},
+            })
+        );
+    }
+}
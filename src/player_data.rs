@@ -1,7 +1,6 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
 use anime_game_data::{AnimeGameData, Property, SkillType};
-use anyhow::Result;
 pub use auto_artifactarium::Achievement;
 pub use auto_artifactarium::r#gen::protos::{AvatarInfo, Item};
 use indexmap::IndexMap;
@@ -30,6 +29,72 @@ pub struct ExportSettings {
     pub min_weapon_rarity: u32,
 }
 
+impl Default for ExportSettings {
+    fn default() -> Self {
+        Self {
+            include_characters: true,
+            include_artifacts: true,
+            include_weapons: true,
+            include_materials: true,
+            fake_initialize_4th_line: false,
+            min_character_level: 1,
+            min_character_ascension: 0,
+            min_character_constellation: 0,
+            min_artifact_level: 0,
+            min_artifact_rarity: 5,
+            min_weapon_level: 1,
+            min_weapon_refinement: 0,
+            min_weapon_ascension: 0,
+            min_weapon_rarity: 3,
+        }
+    }
+}
+
+/// Game ids seen in player data that have no entry in the game database.
+///
+/// These are dropped from exports, so a non-empty report usually means the
+/// embedded game data is older than the game version being captured.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct UnmappedIds {
+    pub characters: BTreeSet<u32>,
+    pub artifacts: BTreeSet<u32>,
+    pub affixes: BTreeSet<u32>,
+    pub weapons: BTreeSet<u32>,
+    pub materials: BTreeSet<u32>,
+    pub properties: BTreeSet<u32>,
+}
+
+impl UnmappedIds {
+    pub fn len(&self) -> usize {
+        self.categories().map(|(_, ids)| ids.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn categories(&self) -> impl Iterator<Item = (&'static str, &BTreeSet<u32>)> {
+        [
+            ("character", &self.characters),
+            ("artifact", &self.artifacts),
+            ("affix", &self.affixes),
+            ("weapon", &self.weapons),
+            ("material", &self.materials),
+            ("property", &self.properties),
+        ]
+        .into_iter()
+    }
+
+    /// Log a warning for every id that is not already present in `previous`.
+    pub fn warn_new(&self, previous: &UnmappedIds) {
+        for ((category, ids), (_, previous_ids)) in self.categories().zip(previous.categories()) {
+            for id in ids.difference(previous_ids) {
+                tracing::warn!("Unmapped {category} id {id} dropped from export");
+            }
+        }
+    }
+}
+
 pub struct PlayerData {
     game_data: AnimeGameData,
     achievements: Vec<Achievement>,
@@ -69,7 +134,66 @@ impl PlayerData {
         self.items = items.into();
     }
 
-    pub fn export_genshin_optimizer(&self, settings: &ExportSettings) -> Result<String> {
+    /// Collect every id that an export would drop because the game database
+    /// does not know about it.
+    pub fn unmapped_ids(&self) -> UnmappedIds {
+        let mut unmapped = UnmappedIds::default();
+
+        for character in &self.characters {
+            if character.avatar_type == 1
+                && self.game_data.get_character(character.avatar_id).is_err()
+            {
+                unmapped.characters.insert(character.avatar_id);
+            }
+        }
+
+        for id in self.character_equip_guid_map.values() {
+            if self.game_data.get_character(*id).is_err() {
+                unmapped.characters.insert(*id);
+            }
+        }
+
+        for item in &self.items {
+            if item.has_material() {
+                if self.game_data.get_material(item.item_id).is_err() {
+                    unmapped.materials.insert(item.item_id);
+                }
+                continue;
+            }
+
+            if !item.has_equip() {
+                continue;
+            }
+            let equip = item.equip();
+
+            if equip.has_reliquary() {
+                if self.game_data.get_artifact(item.item_id).is_err() {
+                    unmapped.artifacts.insert(item.item_id);
+                }
+                let artifact = equip.reliquary();
+                for substat_id in artifact
+                    .append_prop_id_list
+                    .iter()
+                    .chain(artifact.unactivated_prop_id_list.iter())
+                {
+                    if self.game_data.get_affix(*substat_id).is_err() {
+                        unmapped.affixes.insert(*substat_id);
+                    }
+                }
+                if self.game_data.get_property(artifact.main_prop_id).is_err() {
+                    unmapped.properties.insert(artifact.main_prop_id);
+                }
+            } else if equip.has_weapon() && self.game_data.get_weapon(item.item_id).is_err() {
+                unmapped.weapons.insert(item.item_id);
+            }
+        }
+
+        unmapped
+    }
+
+    /// Build a Genshin Optimizer export.  Command handlers may add to it
+    /// before it is serialized.
+    pub fn export_genshin_optimizer(&self, settings: &ExportSettings) -> good::Good {
         let mut good = good::Good {
             format: "GOOD".to_string(),
             version: 3,
@@ -101,9 +225,7 @@ impl PlayerData {
             good.materials = self.export_genshin_optimizer_materials();
         }
 
-        let json = serde_json::to_string(&good)?;
-        tracing::trace!("{json}");
-        Ok(json)
+        good
     }
 
     pub fn export_genshin_optimizer_characters(
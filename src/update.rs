@@ -93,9 +93,26 @@ async fn download_new_version_and_replace_current(release: Release) -> Result<()
     }
     drop(tmp_exe);
 
+    // File capabilities don't survive replacing the binary.
+    #[cfg(target_os = "linux")]
+    let had_capability = crate::admin::has_file_capture_capability();
+    #[cfg(target_os = "linux")]
+    let current_exe = ::std::env::current_exe()?;
+
     tracing::info!("replacing current exe");
     self_update::self_replace::self_replace(tmp_exe_path)?;
 
+    #[cfg(target_os = "linux")]
+    if had_capability {
+        let granted = tokio::task::spawn_blocking(move || {
+            crate::admin::grant_capture_capability(&current_exe)
+        })
+        .await?;
+        if let Err(e) = granted {
+            tracing::warn!("Unable to re-apply capture permissions after update: {e:#}");
+        }
+    }
+
     Ok(())
 }
 
@@ -15,6 +15,8 @@ use tokio::fs;
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::sync::{mpsc, watch};
 
+use crate::events::{self, Event};
+
 pub struct Wish {
     url_tx: watch::Sender<Option<String>>,
     output_log_path: PathBuf,
@@ -154,6 +156,7 @@ impl Wish {
 
         tracing::info!("found {url}");
         self.prev_url = url.to_string();
+        events::emit(Event::WishUrl { url: &url });
         let _ = self.url_tx.send(Some(url));
 
         Ok(())